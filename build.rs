@@ -0,0 +1,30 @@
+use std::process::Command;
+
+/// Run git and return trimmed stdout, or `None` if git is unavailable
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn main() {
+    let commit = git(&["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    let commit_date = git(&["log", "-1", "--format=%cd", "--date=short"]).unwrap_or_else(|| "unknown".to_string());
+    // Only refreshed when this script re-runs, i.e. when HEAD moves, not
+    // on every rebuild of the sources
+    let build_time = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+
+    println!("cargo:rustc-env=VVOSS_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=VVOSS_GIT_COMMIT_DATE={}", commit_date);
+    println!("cargo:rustc-env=VVOSS_BUILD_TIME={}", build_time);
+
+    // Re-run when HEAD moves so the embedded commit stays current
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-changed=build.rs");
+}
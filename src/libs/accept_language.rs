@@ -0,0 +1,206 @@
+use actix_web::HttpRequest;
+
+/// Longest subtag allowed by BCP 47
+const MAX_SUBTAG_LEN: usize = 8;
+
+/// One entry of an `Accept-Language` header
+#[derive(Clone, Debug, PartialEq)]
+pub struct Range {
+    /// Language range as sent, e.g. `de-CH` or `*`
+    pub tag: String,
+    /// Weight in thousandths, 0..=1000
+    pub q: u16,
+}
+
+/// Outcome of negotiating against the configured languages
+#[derive(Clone, Debug, PartialEq)]
+pub struct Negotiated<'a> {
+    /// The configured language chosen
+    pub language: &'a str,
+    /// The range from the header that selected it
+    pub range: String,
+}
+
+/// Parse an `Accept-Language` header, most preferred first
+///
+/// Malformed entries are skipped; entries of equal weight keep their order.
+pub fn parse(header: &str) -> Vec<Range> {
+    let mut ranges: Vec<Range> = header.split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim();
+            if !valid_range(tag) {
+                return None;
+            }
+            let mut q = 1000;
+            for param in parts {
+                let (key, value) = param.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("q") {
+                    q = parse_q(value.trim())?;
+                }
+            }
+            Some(Range { tag: tag.to_string(), q })
+        })
+        .collect();
+    ranges.sort_by_key(|range| std::cmp::Reverse(range.q));
+    ranges
+}
+
+/// `*` or subtags of 1-8 alphanumerics, the first alphabetic
+fn valid_range(tag: &str) -> bool {
+    if tag == "*" {
+        return true;
+    }
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or("");
+    let valid = |s: &str| !s.is_empty() && s.len() <= MAX_SUBTAG_LEN && s.bytes().all(|b| b.is_ascii_alphanumeric());
+    valid(primary) && primary.bytes().all(|b| b.is_ascii_alphabetic()) && subtags.all(valid)
+}
+
+/// `qvalue = ( "0" [ "." 0*3DIGIT ] ) / ( "1" [ "." 0*3("0") ] )`
+fn parse_q(value: &str) -> Option<u16> {
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+    if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths: u16 = format!("{:0<3}", frac).parse().ok()?;
+    match int {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Pick the best of `available` for `header` using RFC 4647 lookup
+///
+/// Each range, in order of preference, is matched case-insensitively and
+/// then truncated subtag by subtag (`zh-Hant-TW`, `zh-Hant`, `zh`) until a
+/// configured language matches. `*` stands for the first configured
+/// language. Ranges with `q=0` exclude a language instead.
+pub fn negotiate<'a>(header: &str, available: &'a [String]) -> Option<Negotiated<'a>> {
+    let ranges = parse(header);
+    let rejected = |language: &str| {
+        ranges.iter().any(|r| r.q == 0 && r.tag.eq_ignore_ascii_case(language))
+    };
+
+    for range in ranges.iter().filter(|r| r.q > 0) {
+        let found = if range.tag == "*" {
+            available.iter().find(|l| !rejected(l))
+        } else {
+            lookup(&range.tag, available).filter(|l| !rejected(l))
+        };
+        if let Some(language) = found {
+            return Some(Negotiated { language, range: range.tag.clone() });
+        }
+    }
+    None
+}
+
+fn lookup<'a>(tag: &str, available: &'a [String]) -> Option<&'a String> {
+    let mut candidate = tag;
+    loop {
+        if let Some(language) = available.iter().find(|l| l.eq_ignore_ascii_case(candidate)) {
+            return Some(language);
+        }
+        let (rest, _) = candidate.rsplit_once('-')?;
+        // A single-letter subtag (extension or private use) is dropped
+        // together with the subtag following it
+        candidate = match rest.rsplit_once('-') {
+            Some((shorter, last)) if last.len() == 1 => shorter,
+            _ => rest,
+        };
+    }
+}
+
+/// Negotiate the request's `Accept-Language` header
+pub fn from_request<'a>(req: &HttpRequest, available: &'a [String]) -> Option<Negotiated<'a>> {
+    let header = req.headers().get("accept-language")?.to_str().ok()?;
+    negotiate(header, available)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn available(languages: &[&str]) -> Vec<String> {
+        languages.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let cases: &[(&str, &[(&str, u16)])] = &[
+            ("", &[]),
+            ("de", &[("de", 1000)]),
+            ("de-CH, en;q=0.5", &[("de-CH", 1000), ("en", 500)]),
+            ("en;q=0.5, de;q=0.9", &[("de", 900), ("en", 500)]),
+            ("fr;q=0.8, de;q=0.8, en", &[("en", 1000), ("fr", 800), ("de", 800)]),
+            ("de;q=1.000, en;q=0", &[("de", 1000), ("en", 0)]),
+            ("de ; q = 0.7", &[("de", 700)]),
+            ("*;q=0.1", &[("*", 100)]),
+            ("zh-Hant-TW", &[("zh-Hant-TW", 1000)]),
+            // Malformed entries are skipped, the rest still counts
+            ("de;q=1.5, en", &[("en", 1000)]),
+            ("de;q=0.1234, en", &[("en", 1000)]),
+            ("de;q=abc, en", &[("en", 1000)]),
+            ("1de, en", &[("en", 1000)]),
+            ("de_DE, en", &[("en", 1000)]),
+            ("toolongsubtag, en", &[("en", 1000)]),
+            ("de-, en", &[("en", 1000)]),
+            (",,,", &[]),
+        ];
+
+        for (header, expected) in cases {
+            let expected: Vec<Range> = expected.iter()
+                .map(|(tag, q)| Range { tag: tag.to_string(), q: *q })
+                .collect();
+            assert_eq!(parse(header), expected, "header {:?}", header);
+        }
+    }
+
+    /// Header, configured languages, expected (language, winning range)
+    type Case = (&'static str, &'static [&'static str], Option<(&'static str, &'static str)>);
+
+    #[test]
+    fn negotiates_languages() {
+        let cases: &[Case] = &[
+            ("de", &["de", "en"], Some(("de", "de"))),
+            ("en-US,en;q=0.9", &["de", "en"], Some(("en", "en-US"))),
+            // Weights win over order
+            ("en;q=0.5, de;q=0.9", &["de", "en"], Some(("de", "de"))),
+            ("de;q=0.1, en;q=0.2", &["de", "en"], Some(("en", "en"))),
+            // Unavailable preferences fall through to the next one
+            ("fr-FR, fr;q=0.9, de;q=0.5", &["de", "en"], Some(("de", "de"))),
+            ("fr, es", &["de", "en"], None),
+            // Case-insensitive, configured spelling is returned
+            ("DE-at", &["de", "en"], Some(("de", "DE-at"))),
+            ("zh-hant-tw", &["zh-Hant", "zh"], Some(("zh-Hant", "zh-hant-tw"))),
+            ("zh-Hant", &["en", "zh"], Some(("zh", "zh-Hant"))),
+            // Lookup never widens a range to a more specific language
+            ("de", &["de-CH", "en"], None),
+            // Single-letter subtags go together with what follows them
+            ("de-DE-x-foo", &["de-DE", "en"], Some(("de-DE", "de-DE-x-foo"))),
+            ("en-a-bbb-x-a-ccc", &["en"], Some(("en", "en-a-bbb-x-a-ccc"))),
+            // Wildcard means any, i.e. the default
+            ("*", &["de", "en"], Some(("de", "*"))),
+            ("fr, *;q=0.5", &["de", "en"], Some(("de", "*"))),
+            ("de;q=0, *", &["de", "en"], Some(("en", "*"))),
+            // q=0 rules a language out
+            ("de;q=0", &["de", "en"], None),
+            ("de-CH, de;q=0", &["de", "en"], None),
+            ("de;q=0, en", &["de", "en"], Some(("en", "en"))),
+            // Garbage
+            ("", &["de", "en"], None),
+            ("x", &["de", "en"], None),
+            ("*-de", &["de", "en"], None),
+            ("de", &[], None),
+        ];
+
+        for (header, languages, expected) in cases {
+            let languages = available(languages);
+            let negotiated = negotiate(header, &languages)
+                .map(|n| (n.language.to_string(), n.range));
+            let expected = expected.map(|(language, range)| (language.to_string(), range.to_string()));
+            assert_eq!(negotiated, expected, "header {:?} against {:?}", header, languages);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Number of hex characters of the content hash kept in file names
+const HASH_LEN: usize = 10;
+
+/// Maps logical asset paths to content-hashed file names and back,
+/// e.g. `css/base.css` <-> `css/base.3f2a9c1b7e.css`
+#[derive(Clone, Default)]
+pub struct AssetManifest {
+    by_logical: HashMap<String, String>,
+    by_hashed: HashMap<String, String>,
+}
+
+impl AssetManifest {
+    /// Hash every file below the static root
+    pub fn from_dir(root: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut manifest = AssetManifest::default();
+        manifest.scan(Path::new(root), "")?;
+        Ok(manifest)
+    }
+
+    fn scan(&mut self, dir: &Path, prefix: &str) -> Result<(), Box<dyn std::error::Error>> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Hidden files and precompressed siblings are not assets
+            if name.starts_with('.') || name.ends_with(".br") || name.ends_with(".gz") {
+                continue;
+            }
+
+            let logical = format!("{}{}", prefix, name);
+            let path = entry.path();
+            if path.is_dir() {
+                self.scan(&path, &format!("{}/", logical))?;
+            } else {
+                let digest = Sha256::digest(std::fs::read(&path)?);
+                let hash: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+                let hashed = hashed_name(&logical, &hash[..HASH_LEN]);
+                self.by_hashed.insert(hashed.clone(), logical.clone());
+                self.by_logical.insert(logical, hashed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Public URL for a logical asset path, unhashed if it is unknown
+    pub fn url(&self, logical: &str) -> String {
+        let logical = logical.trim_start_matches('/');
+        match self.by_logical.get(logical) {
+            Some(hashed) => format!("/static/{}", hashed),
+            None => {
+                warn!("Asset not in manifest: {}", logical);
+                format!("/static/{}", logical)
+            }
+        }
+    }
+
+    /// Logical path for a hashed file name, if it is one
+    pub fn logical_for(&self, hashed: &str) -> Option<&str> {
+        self.by_hashed.get(hashed.trim_start_matches('/')).map(|s| s.as_str())
+    }
+}
+
+/// Tera function `asset(path="css/base.css")`
+impl tera::Function for AssetManifest {
+    fn call(&self, args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+        let path = args.get("path")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tera::Error::msg("asset() requires a `path` string argument"))?;
+        Ok(tera::Value::String(self.url(path)))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// Insert the hash before the last extension of the file name
+fn hashed_name(logical: &str, hash: &str) -> String {
+    let (dir, file) = match logical.rfind('/') {
+        Some(i) => logical.split_at(i + 1),
+        None => ("", logical),
+    };
+    match file.rfind('.') {
+        Some(i) if i > 0 => format!("{}{}.{}{}", dir, &file[..i], hash, &file[i..]),
+        _ => format!("{}{}.{}", dir, file, hash),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tera::Tera;
+
+    fn manifest() -> AssetManifest {
+        AssetManifest::from_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/static")).unwrap()
+    }
+
+    #[test]
+    fn hash_goes_before_last_extension() {
+        assert_eq!(hashed_name("css/base.css", "3f2a9c1b7e"), "css/base.3f2a9c1b7e.css");
+        assert_eq!(hashed_name("js/app.min.js", "3f2a9c1b7e"), "js/app.min.3f2a9c1b7e.js");
+        assert_eq!(hashed_name("LICENSE", "3f2a9c1b7e"), "LICENSE.3f2a9c1b7e");
+        assert_eq!(hashed_name("img/.hidden", "3f2a9c1b7e"), "img/.hidden.3f2a9c1b7e");
+        assert_eq!(hashed_name("v1.2/readme", "3f2a9c1b7e"), "v1.2/readme.3f2a9c1b7e");
+    }
+
+    #[test]
+    fn maps_logical_and_hashed_names() {
+        let manifest = manifest();
+        let url = manifest.url("/css/base.css");
+        let hashed = url.strip_prefix("/static/").unwrap();
+        let hash = hashed.strip_prefix("css/base.").and_then(|s| s.strip_suffix(".css")).unwrap();
+        assert_eq!(hash.len(), HASH_LEN);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(manifest.url("css/base.css"), url);
+
+        assert_eq!(manifest.logical_for(hashed), Some("css/base.css"));
+        assert_eq!(manifest.logical_for(&format!("/{}", hashed)), Some("css/base.css"));
+        assert_eq!(manifest.logical_for("css/base.css"), None);
+        assert_eq!(manifest.logical_for("css/base.0000000000.css"), None);
+    }
+
+    #[test]
+    fn unknown_assets_stay_unhashed() {
+        let manifest = manifest();
+        assert_eq!(manifest.url("css/missing.css"), "/static/css/missing.css");
+        assert_eq!(manifest.url("/img/missing.png"), "/static/img/missing.png");
+    }
+
+    #[test]
+    fn tera_function_renders_urls() {
+        let manifest = manifest();
+        let mut tera = Tera::default();
+        tera.register_function("asset", manifest.clone());
+        tera.add_raw_template("page", r#"<link href="{{ asset(path="css/base.css") }}">"#).unwrap();
+        tera.add_raw_template("missing", r#"{{ asset() }}"#).unwrap();
+
+        let rendered = tera.render("page", &tera::Context::new()).unwrap();
+        assert_eq!(rendered, format!(r#"<link href="{}">"#, manifest.url("css/base.css")));
+        assert!(tera.render("missing", &tera::Context::new()).is_err());
+    }
+}
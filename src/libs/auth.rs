@@ -1,29 +1,379 @@
-use actix_web::{dev::ServiceRequest, Error};
+use actix_web::error::InternalError;
+use actix_web::{dev::ServiceRequest, web, Error, HttpMessage};
 use actix_web_httpauth::extractors::basic::BasicAuth;
 use actix_web_httpauth::extractors::AuthenticationError;
 use actix_web_httpauth::headers::www_authenticate::basic::Basic;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier};
+use log::warn;
+use std::collections::HashMap;
 
-use super::config::Config;
+use super::config::{Access, AuthConfig, AuthRule};
+use super::health;
+use super::logging;
+use super::metrics::metrics;
+use super::proxy::ClientAddr;
+use super::ratelimit::too_many_requests;
+use super::state::State;
+
+/// Password hashed into the dummy verified for unknown users
+const DUMMY_PASSWORD: &str = "vvoss-dummy-password";
+
+/// Fixed salt of the dummy hash, the 16 bytes bcrypt requires
+const DUMMY_SALT: [u8; 16] = *b"vvoss-dummy-salt";
+
+/// Name of the user whose credentials the validator accepted, stored in
+/// the request extensions for the access log
+#[derive(Clone)]
+pub struct AuthenticatedUser(pub String);
+
+/// Username to password hash map, merged from `[[auth.users]]` and the
+/// optional htpasswd file
+#[derive(Clone, Default)]
+pub struct Credentials {
+    users: HashMap<String, String>,
+    /// Verified for unknown users so that lookups take the same time
+    /// whether or not the username exists
+    dummy: Option<String>,
+}
+
+impl Credentials {
+    /// Build the credential store from `[auth]`, reading the htpasswd file if set
+    pub fn from_config(auth: &AuthConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut users = HashMap::new();
+
+        if let Some(path) = &auth.htpasswd {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| format!("{}: {}", path, e))?;
+            users.extend(parse_htpasswd(&content).map_err(|e| format!("{}:{}", path, e))?);
+        }
+
+        // Entries in config.toml take precedence over the htpasswd file
+        for user in &auth.users {
+            check_hash(&user.password_hash)
+                .map_err(|e| format!("auth.users {}: {}", user.username, e))?;
+            users.insert(user.username.clone(), user.password_hash.clone());
+        }
+
+        if auth.enabled && users.is_empty() {
+            return Err("auth is enabled but no users are configured; set \
+                VVOSS_AUTH__USERS__<NAME> to an argon2 or bcrypt hash or point auth.htpasswd at a file".into());
+        }
+
+        // Modelled on the first user's hash, so that an unknown name costs
+        // what a known one does
+        let dummy = users.keys().min()
+            .map(|user| dummy_hash(&users[user]))
+            .transpose()?;
+
+        Ok(Credentials { users, dummy })
+    }
+
+    /// Check a username/password pair
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        match self.users.get(username) {
+            Some(hash) => verify_hash(hash, password),
+            None => {
+                if let Some(dummy) = &self.dummy {
+                    verify_hash(dummy, password);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// `user:hash` lines of an htpasswd file; errors start with the line number
+fn parse_htpasswd(content: &str) -> Result<Vec<(String, String)>, String> {
+    let mut users = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (user, hash) = line.split_once(':')
+            .ok_or_else(|| format!("{}: expected user:hash", i + 1))?;
+        check_hash(hash).map_err(|e| format!("{}: {}", i + 1, e))?;
+        users.push((user.to_string(), hash.to_string()));
+    }
+    Ok(users)
+}
+
+/// A hash of [`DUMMY_PASSWORD`] with the algorithm and cost of `like`
+fn dummy_hash(like: &str) -> Result<String, String> {
+    if like.starts_with("$argon2") {
+        let parsed = PasswordHash::new(like).map_err(|e| e.to_string())?;
+        let params = Params::try_from(&parsed).map_err(|e| e.to_string())?;
+        let salt = SaltString::encode_b64(&DUMMY_SALT).map_err(|e| e.to_string())?;
+        Argon2::default()
+            .hash_password_customized(DUMMY_PASSWORD.as_bytes(), Some(parsed.algorithm), parsed.version, params, &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string())
+    } else {
+        let cost = like.get(4..6).and_then(|cost| cost.parse().ok())
+            .ok_or("bcrypt hash without a cost")?;
+        bcrypt::hash_with_salt(DUMMY_PASSWORD, cost, DUMMY_SALT)
+            .map(|hash| hash.format_for_version(bcrypt::Version::TwoB))
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Validate that a hash is in a supported format
+fn check_hash(hash: &str) -> Result<(), String> {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).map(|_| ()).map_err(|e| e.to_string())
+    } else if is_bcrypt(hash) {
+        Ok(())
+    } else {
+        Err("unsupported hash, expected argon2 or bcrypt".to_string())
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|p| hash.starts_with(p))
+}
+
+/// Verify a password against an argon2 or bcrypt hash
+///
+/// Both algorithms compare the derived digest in constant time.
+fn verify_hash(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+            .unwrap_or(false)
+    } else if is_bcrypt(hash) {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        false
+    }
+}
+
+/// Find the most specific rule whose path is a segment-wise prefix of `path`
+pub fn match_rule<'a>(rules: &'a [AuthRule], path: &str) -> Option<&'a AuthRule> {
+    match_path(rules, path, |rule| &rule.path)
+}
+
+/// Find the item with the most specific path pattern matching `path`
+///
+/// Patterns match segment-wise prefixes; `{...}` segments match any single
+/// segment, and literal segments win over them.
+pub fn match_path<'a, T>(items: &'a [T], path: &str, pattern: impl Fn(&T) -> &str) -> Option<&'a T> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    items.iter()
+        .filter_map(|rule| {
+            let pattern: Vec<&str> = pattern(rule).split('/').filter(|s| !s.is_empty()).collect();
+            if pattern.len() > segments.len() {
+                return None;
+            }
+            let mut literals = 0;
+            for (p, s) in pattern.iter().zip(&segments) {
+                if p.starts_with('{') && p.ends_with('}') {
+                    continue;
+                }
+                if p != s {
+                    return None;
+                }
+                literals += 1;
+            }
+            Some((pattern.len(), literals, rule))
+        })
+        .max_by_key(|(len, literals, _)| (*len, *literals))
+        .map(|(_, _, rule)| rule)
+}
+
+/// Rule and access for a request path
+///
+/// `path` must be the percent-decoded path the router matches
+/// (`match_info().as_str()`), so that `/de/%70ortfolio` is treated like
+/// `/de/portfolio`. Probes are public unless a rule covers them explicitly.
+fn access<'a>(auth: &'a AuthConfig, path: &str) -> (Option<&'a AuthRule>, Access) {
+    let rule = match_rule(&auth.rules, path);
+    let access = match rule {
+        Some(rule) => rule.access,
+        None if health::PATHS.contains(&path) => Access::Public,
+        None => auth.default,
+    };
+    (rule, access)
+}
 
 pub async fn validator(
     req: ServiceRequest,
-    credentials: BasicAuth,
+    credentials: Option<BasicAuth>,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
-    let config = req.app_data::<actix_web::web::Data<Config>>()
-        .map(|c| c.get_ref().clone())
-        .unwrap();
+    let shared = req.app_data::<web::Data<State>>().unwrap().clone();
+    let state = shared.current();
+    let config = &state.config;
 
     if !config.auth.enabled {
         return Ok(req);
     }
 
-    match (credentials.user_id(), credentials.password()) {
-        (user, Some(pass)) if user == config.auth.username && pass == config.auth.password => {
-            Ok(req)
+    let (rule, access) = access(&config.auth, req.match_info().as_str());
+    if access == Access::Public {
+        return Ok(req);
+    }
+
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            metrics().auth_failure("missing");
+            let challenge = Basic::default();
+            return Err((AuthenticationError::new(challenge).into(), req));
+        }
+    };
+
+    // Locked-out clients are turned away before their password is checked
+    let client = ClientAddr::of(req.request()).0;
+    if let Some(wait) = shared.limiter().locked_out(&config.rate_limit, client) {
+        metrics().rate_limited("lockout");
+        let response = too_many_requests(wait);
+        return Err((InternalError::from_response("locked out", response).into(), req));
+    }
+
+    let store = state.credentials.clone();
+
+    // Hash verification is deliberately slow, keep it off the worker thread
+    let user = credentials.user_id().to_string();
+    let pass = credentials.password().map(|p| p.to_string());
+    let valid = match pass {
+        Some(pass) => {
+            let user = user.clone();
+            logging::block(move || store.verify(&user, &pass)).await.unwrap_or(false)
+        }
+        None => false,
+    };
+
+    if !valid {
+        metrics().auth_failure("invalid");
+        if let Some(wait) = shared.limiter().record_failure(&config.rate_limit, client) {
+            warn!("Locking out {} for {}s after repeated auth failures",
+                client.map_or("unix socket peer".to_string(), |ip| ip.to_string()), wait.as_secs());
+        }
+        let challenge = Basic::default();
+        return Err((AuthenticationError::new(challenge).into(), req));
+    }
+
+    shared.limiter().record_success(client);
+
+    match rule {
+        Some(rule) if !rule.users.is_empty() && !rule.users.contains(&user) => {
+            metrics().auth_failure("forbidden");
+            Err((actix_web::error::ErrorForbidden("Forbidden"), req))
         }
         _ => {
-            let challenge = Basic::default();
-            Err((AuthenticationError::new(challenge).into(), req))
+            req.extensions_mut().insert(AuthenticatedUser(user));
+            Ok(req)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::config::UserConfig;
+    use actix_web::test::TestRequest;
+
+    fn rule(path: &str, access: Access) -> AuthRule {
+        AuthRule { path: path.to_string(), access, users: Vec::new() }
+    }
+
+    fn auth(default: Access) -> AuthConfig {
+        AuthConfig {
+            enabled: true,
+            htpasswd: None,
+            users: Vec::new(),
+            default,
+            rules: vec![
+                rule("/static", Access::Public),
+                rule("/{lang}/portfolio", Access::Protected),
+            ],
         }
     }
-}
\ No newline at end of file
+
+    /// Access as the validator sees it for a raw request URI
+    fn access_for(auth: &AuthConfig, uri: &str) -> (Option<String>, Access) {
+        let req = TestRequest::with_uri(uri).to_http_request();
+        let (rule, access) = access(auth, req.match_info().as_str());
+        (rule.map(|r| r.path.clone()), access)
+    }
+
+    #[test]
+    fn matches_decoded_paths() {
+        let public = auth(Access::Public);
+        let cases: &[(&str, Option<&str>, Access)] = &[
+            ("/de/portfolio", Some("/{lang}/portfolio"), Access::Protected),
+            ("/de/portfolio/case", Some("/{lang}/portfolio"), Access::Protected),
+            // Encoded characters are decoded as the router decodes them
+            ("/de/%70ortfolio", Some("/{lang}/portfolio"), Access::Protected),
+            ("/de/%70%6F%72%74%66%6F%6C%69%6F", Some("/{lang}/portfolio"), Access::Protected),
+            ("/%64e/portfolio", Some("/{lang}/portfolio"), Access::Protected),
+            // Empty segments do not hide a prefix
+            ("/de//portfolio", Some("/{lang}/portfolio"), Access::Protected),
+            ("//de/portfolio", Some("/{lang}/portfolio"), Access::Protected),
+            // An encoded slash stays part of its segment, for the router too
+            ("/de%2Fportfolio", None, Access::Public),
+            ("/de/portfolio%2F", None, Access::Public),
+            ("/static/css/main.css", Some("/static"), Access::Public),
+            ("/%73tatic/css/main.css", Some("/static"), Access::Public),
+            ("/de/", None, Access::Public),
+        ];
+
+        for (uri, rule, access) in cases {
+            assert_eq!(access_for(&public, uri), (rule.map(String::from), *access), "uri {:?}", uri);
+        }
+    }
+
+    #[test]
+    fn probes_are_public_unless_a_rule_covers_them() {
+        let mut protected = auth(Access::Protected);
+        assert_eq!(access_for(&protected, "/healthz").1, Access::Public);
+        assert_eq!(access_for(&protected, "/%68ealthz").1, Access::Public);
+        assert_eq!(access_for(&protected, "/healthz/x").1, Access::Protected);
+        assert_eq!(access_for(&protected, "/de/").1, Access::Protected);
+
+        protected.rules.push(rule("/status", Access::Protected));
+        assert_eq!(access_for(&protected, "/%73tatus").1, Access::Protected);
+    }
+
+    #[test]
+    fn parses_htpasswd_lines() {
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        let content = format!("# comment\n\n  alice:{}\n", bcrypt);
+        assert_eq!(parse_htpasswd(&content).unwrap(), vec![("alice".to_string(), bcrypt.clone())]);
+
+        let err = parse_htpasswd(&format!("alice:{}\nbob\n", bcrypt)).unwrap_err();
+        assert!(err.starts_with("2: "), "{}", err);
+        let err = parse_htpasswd("bob:{SHA}abc\n").unwrap_err();
+        assert!(err.starts_with("1: unsupported hash"), "{}", err);
+    }
+
+    #[test]
+    fn dummy_hash_matches_algorithm_and_cost() {
+        let bcrypt = bcrypt::hash("secret", 5).unwrap();
+        let dummy = dummy_hash(&bcrypt).unwrap();
+        assert!(dummy.starts_with("$2b$05$"), "{}", dummy);
+        assert!(verify_hash(&dummy, DUMMY_PASSWORD));
+
+        let salt = SaltString::encode_b64(b"0123456789abcdef").unwrap();
+        let params = Params::new(1024, 1, 1, None).unwrap();
+        let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password(b"secret", &salt).unwrap().to_string();
+        let dummy = dummy_hash(&argon2).unwrap();
+        assert!(dummy.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"), "{}", dummy);
+        assert!(verify_hash(&dummy, DUMMY_PASSWORD));
+    }
+
+    #[test]
+    fn unknown_users_are_rejected() {
+        let mut config = auth(Access::Protected);
+        config.users.push(UserConfig {
+            username: "alice".to_string(),
+            password_hash: bcrypt::hash("secret", 4).unwrap(),
+        });
+        let credentials = Credentials::from_config(&config).unwrap();
+        assert!(credentials.verify("alice", "secret"));
+        assert!(!credentials.verify("alice", "wrong"));
+        assert!(!credentials.verify("mallory", DUMMY_PASSWORD));
+    }
+}
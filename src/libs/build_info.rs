@@ -0,0 +1,30 @@
+use serde::Serialize;
+
+/// Provenance embedded by `build.rs` at compile time
+#[derive(Serialize, Clone, Copy)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: &'static str,
+    pub commit_date: &'static str,
+    /// When `build.rs` last ran, which is on the first build after the
+    /// checked-out commit changed; later rebuilds keep this time
+    pub build_time: &'static str,
+}
+
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    version: env!("CARGO_PKG_VERSION"),
+    commit: env!("VVOSS_GIT_COMMIT"),
+    commit_date: env!("VVOSS_GIT_COMMIT_DATE"),
+    build_time: env!("VVOSS_BUILD_TIME"),
+};
+
+impl BuildInfo {
+    /// Date shown as "latest update", the build date when git was unavailable
+    pub fn latest_update(&self) -> &'static str {
+        if self.commit_date == "unknown" {
+            &self.build_time[..10]
+        } else {
+            self.commit_date
+        }
+    }
+}
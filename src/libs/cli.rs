@@ -0,0 +1,115 @@
+/// Command line arguments
+///
+/// `vvoss-web [COMMAND...] [--config PATH] [--set KEY=VALUE]...`
+#[derive(Default)]
+pub struct Cli {
+    /// Positional arguments, e.g. `precompress`
+    pub command: Vec<String>,
+    pub config_path: Option<String>,
+    pub overrides: Vec<(String, String)>,
+    /// `--help` was given
+    pub help: bool,
+}
+
+pub const USAGE: &str = "\
+Usage: vvoss-web [COMMAND] [OPTIONS]
+
+Commands:
+    (none)         Run the web server
+    precompress    Write .br/.gz siblings for the static directory
+    i18n check     Report missing, duplicate, orphaned and untranslated strings
+                   and placeholder mismatches; exits with 1 if there are errors
+
+Options:
+    -c, --config PATH      Config file (default: config.toml, optional)
+    -s, --set KEY=VALUE    Override a config key, e.g. server.workers=8
+    -h, --help             Show this help
+
+Environment:
+    VVOSS_<SECTION>__<KEY>  Override a config key, e.g. VVOSS_LOGGING__LEVEL=debug;
+                            keys are lowercased, use --set for mixed-case ones;
+                            arrays of tables such as auth.rules take a TOML value";
+
+impl Cli {
+    /// Parse arguments without the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut cli = Cli::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg.clone(), None),
+            };
+
+            match flag.as_str() {
+                "-c" | "--config" => {
+                    let value = inline.or_else(|| args.next())
+                        .ok_or("--config requires a path")?;
+                    cli.config_path = Some(value);
+                }
+                "-s" | "--set" => {
+                    let value = inline.or_else(|| args.next())
+                        .ok_or("--set requires KEY=VALUE")?;
+                    let (key, value) = value.split_once('=')
+                        .ok_or_else(|| format!("--set expects KEY=VALUE, got `{}`", value))?;
+                    cli.overrides.push((key.to_string(), value.to_string()));
+                }
+                "-h" | "--help" => cli.help = true,
+                _ if arg.starts_with('-') => {
+                    return Err(format!("unknown option `{}`\n\n{}", arg, USAGE));
+                }
+                _ => cli.command.push(arg),
+            }
+        }
+
+        Ok(cli)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, String> {
+        Cli::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parses_commands_and_options() {
+        let cli = parse(&[]).unwrap();
+        assert!(cli.command.is_empty() && cli.config_path.is_none() && cli.overrides.is_empty() && !cli.help);
+
+        let cli = parse(&[
+            "i18n", "check", "-c", "/etc/vvoss.toml",
+            "--set", "server.workers=8", "--set=auth.users.Alice=$argon2id$v=19$a=b", "-s", "logging.level=",
+        ]).unwrap();
+        assert_eq!(cli.command, ["i18n", "check"]);
+        assert_eq!(cli.config_path.as_deref(), Some("/etc/vvoss.toml"));
+        assert_eq!(cli.overrides, [
+            ("server.workers".to_string(), "8".to_string()),
+            ("auth.users.Alice".to_string(), "$argon2id$v=19$a=b".to_string()),
+            ("logging.level".to_string(), String::new()),
+        ]);
+
+        let cli = parse(&["--config=local.toml", "-h"]).unwrap();
+        assert_eq!(cli.config_path.as_deref(), Some("local.toml"));
+        assert!(cli.help);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        for (args, expected) in [
+            (&["--config"][..], "--config requires a path"),
+            (&["-c"][..], "--config requires a path"),
+            (&["--set"][..], "--set requires KEY=VALUE"),
+            (&["-s", "server.workers"][..], "--set expects KEY=VALUE, got `server.workers`"),
+            (&["--set=workers"][..], "--set expects KEY=VALUE, got `workers`"),
+            (&["--verbose"][..], "unknown option `--verbose`"),
+            (&["--port=80"][..], "unknown option `--port=80`"),
+        ] {
+            let err = parse(args).err().unwrap();
+            assert!(err.starts_with(expected), "{:?}: {}", args, err);
+        }
+    }
+}
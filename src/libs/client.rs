@@ -1,7 +1,10 @@
-use actix_web::HttpRequest;
+use actix_web::{HttpRequest, HttpResponseBuilder};
 use serde::{Deserialize, Serialize};
 use serde_json;
 
+use super::accept_language;
+use super::config::LanguagesConfig;
+
 #[derive(Serialize, Clone)]
 pub struct ClientInfo {
     pub language: String,
@@ -12,6 +15,7 @@ pub struct ClientInfo {
     pub dpr: Option<f32>,
     pub device_type: String,
     pub breakpoint: String,
+    pub save_data: bool,
     pub lang: String,
 }
 
@@ -30,8 +34,7 @@ pub fn parse_screen_info(req: &HttpRequest) -> Option<ScreenInfo> {
         if let Ok(cookies_str) = cookie_header.to_str() {
             for cookie_part in cookies_str.split(';') {
                 let trimmed = cookie_part.trim();
-                if trimmed.starts_with("screen_info=") {
-                    let value = &trimmed[12..];
+                if let Some(value) = trimmed.strip_prefix("screen_info=") {
                     if let Ok(decoded) = urlencoding::decode(value) {
                         if let Ok(screen_info) = serde_json::from_str::<ScreenInfo>(&decoded) {
                             return Some(screen_info);
@@ -44,54 +47,120 @@ pub fn parse_screen_info(req: &HttpRequest) -> Option<ScreenInfo> {
     None
 }
 
-/// Detect client information from headers and cookies
-pub fn detect_client_info(req: &HttpRequest) -> ClientInfo {
-    let screen_info = parse_screen_info(req);
-    
-    // Detect language from Accept-Language header
-    let mut language = "en-EN".to_string();
-    
-    if let Some(accept_lang) = req.headers().get("accept-language") {
-        if let Ok(lang_str) = accept_lang.to_str() {
-            for lang_part in lang_str.split(',') {
-                let lang = lang_part.split(';').next().unwrap_or("").trim();
-                
-                if lang.starts_with("de") {
-                    language = "de-DE".to_string();
-                    break;
-                } else if lang.starts_with("en") {
-                    language = "en-EN".to_string();
-                    break;
-                }
-            }
-        }
+/// Client hints requested from the browser via `Accept-CH`
+pub const ACCEPT_CH: &str = "Viewport-Width, Sec-CH-Viewport-Width, DPR, Sec-CH-DPR, Sec-CH-UA-Mobile, Save-Data";
+
+/// Hints the page cannot be rendered correctly without; supporting browsers
+/// retry the request once with them instead of needing the detection page
+pub const CRITICAL_CH: &str = "Viewport-Width, Sec-CH-Viewport-Width";
+
+/// Query marker set by the detection page when it could not store a cookie
+pub const NO_DETECT_PARAM: &str = "nodetect=1";
+
+#[derive(Default)]
+pub struct ClientHints {
+    pub viewport_width: Option<u32>,
+    pub dpr: Option<f32>,
+    pub mobile: Option<bool>,
+    pub save_data: bool,
+}
+
+fn header_str<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|h| h.to_str().ok()).map(|s| s.trim())
+}
+
+/// Parse client hint request headers, preferring the `Sec-CH-` names
+pub fn parse_client_hints(req: &HttpRequest) -> ClientHints {
+    ClientHints {
+        viewport_width: header_str(req, "sec-ch-viewport-width")
+            .or_else(|| header_str(req, "viewport-width"))
+            .and_then(|v| v.parse().ok()),
+        dpr: header_str(req, "sec-ch-dpr")
+            .or_else(|| header_str(req, "dpr"))
+            .and_then(|v| v.parse().ok()),
+        mobile: header_str(req, "sec-ch-ua-mobile").and_then(|v| match v {
+            "?1" => Some(true),
+            "?0" => Some(false),
+            _ => None,
+        }),
+        save_data: header_str(req, "save-data")
+            .map(|v| v.eq_ignore_ascii_case("on"))
+            .unwrap_or(false),
     }
+}
+
+/// Whether the screen detection page is needed to pick a breakpoint
+///
+/// It is skipped for bots, when client hints or the `screen_info` cookie
+/// already carry the viewport, and after the page itself gave up.
+pub fn needs_screen_detection(req: &HttpRequest) -> bool {
+    !is_bot_request(req)
+        && parse_client_hints(req).viewport_width.is_none()
+        && parse_screen_info(req).is_none()
+        && !req.query_string().split('&').any(|p| p == NO_DETECT_PARAM)
+}
+
+/// Add the client hint negotiation headers to an HTML response
+pub fn append_client_hint_headers(response: &mut HttpResponseBuilder) {
+    response
+        .insert_header(("Accept-CH", ACCEPT_CH))
+        .insert_header(("Critical-CH", CRITICAL_CH))
+        .insert_header(("Vary", "Viewport-Width, Sec-CH-Viewport-Width, DPR, Sec-CH-DPR, Sec-CH-UA-Mobile, Save-Data"));
+}
+
+/// Detect client information from client hints, cookies and headers
+///
+/// With `cookieless` the `screen_info` cookie is ignored and, unless client
+/// hints carry the viewport, `breakpoint` is left empty so the template
+/// can fall back to CSS media queries. `language` is the best match for
+/// `Accept-Language` among the configured languages.
+pub fn detect_client_info(req: &HttpRequest, cookieless: bool, languages: &LanguagesConfig) -> ClientInfo {
+    let hints = parse_client_hints(req);
+    let screen_info = if cookieless { None } else { parse_screen_info(req) };
+    
+    // Preferred configured language, by Accept-Language weights
+    let language = accept_language::from_request(req, &languages.available)
+        .map_or(languages.default_code(), |negotiated| negotiated.language)
+        .to_string();
+
+    // Client hints win over the cookie, which may be stale after a resize
+    let viewport_width = hints.viewport_width
+        .or_else(|| screen_info.as_ref().map(|s| s.viewport_width));
     
     ClientInfo {
         language,
         screen_width: screen_info.as_ref().map(|s| s.width),
         screen_height: screen_info.as_ref().map(|s| s.height),
-        viewport_width: screen_info.as_ref().map(|s| s.viewport_width),
+        viewport_width,
         viewport_height: screen_info.as_ref().map(|s| s.viewport_height),
-        dpr: screen_info.as_ref().map(|s| s.dpr),
-        device_type: detect_device_type(req, &screen_info),
-        breakpoint: detect_breakpoint(req, &screen_info),
+        dpr: hints.dpr.or_else(|| screen_info.as_ref().map(|s| s.dpr)),
+        device_type: detect_device_type(req, viewport_width, hints.mobile),
+        breakpoint: if cookieless && viewport_width.is_none() {
+            String::new()
+        } else {
+            detect_breakpoint(req, viewport_width, hints.mobile)
+        },
+        save_data: hints.save_data,
         lang: String::new(), // Will be set in handler
     }
 }
 
-/// Detect device type from User-Agent and screen info
-pub fn detect_device_type(req: &HttpRequest, screen_info: &Option<ScreenInfo>) -> String {
-    // If we have screen info, use it for better detection
-    if let Some(info) = screen_info {
-        if info.viewport_width <= 559 {
+/// Detect device type from viewport, mobile hint and User-Agent
+pub fn detect_device_type(req: &HttpRequest, viewport_width: Option<u32>, mobile: Option<bool>) -> String {
+    // If we know the viewport, use it for better detection
+    if let Some(width) = viewport_width {
+        if width <= 559 {
             return "mobile".to_string();
-        } else if info.viewport_width <= 959 {
+        } else if width <= 959 {
             return "tablet".to_string();
-        } else if info.viewport_width > 1920 {
+        } else if width > 1920 {
             return "wide".to_string();
         }
     }
+
+    if mobile == Some(true) {
+        return "mobile".to_string();
+    }
     
     // Fallback to User-Agent detection
     if let Some(user_agent) = req.headers().get("user-agent") {
@@ -138,14 +207,14 @@ pub fn detect_device_type(req: &HttpRequest, screen_info: &Option<ScreenInfo>) -
     "desktop".to_string()
 }
 
-/// Determine CSS breakpoint based on device type and screen info
-pub fn detect_breakpoint(req: &HttpRequest, screen_info: &Option<ScreenInfo>) -> String {
-    if let Some(info) = screen_info {
-        if info.viewport_width <= 559 {
+/// Determine CSS breakpoint based on viewport or device type
+pub fn detect_breakpoint(req: &HttpRequest, viewport_width: Option<u32>, mobile: Option<bool>) -> String {
+    if let Some(width) = viewport_width {
+        if width <= 559 {
             return "phone".to_string();
-        } else if info.viewport_width <= 959 {
+        } else if width <= 959 {
             return "tablet".to_string();
-        } else if info.viewport_width <= 1259 {
+        } else if width <= 1259 {
             return "screen".to_string();
         } else {
             return "wide".to_string();
@@ -153,7 +222,7 @@ pub fn detect_breakpoint(req: &HttpRequest, screen_info: &Option<ScreenInfo>) ->
     }
     
     // Fallback to device type detection
-    let device = detect_device_type(req, screen_info);
+    let device = detect_device_type(req, viewport_width, mobile);
     match device.as_str() {
         "mobile" => "phone".to_string(),
         "tablet" => "tablet".to_string(),
@@ -175,27 +244,138 @@ pub fn is_bot_request(req: &HttpRequest) -> bool {
 }
 
 /// Generate screen detection HTML
-pub fn generate_screen_detection_html() -> String {
-    r#"<!DOCTYPE html>
+///
+/// Stores the viewport in a cookie and reloads. If the cookie cannot be
+/// written, or JavaScript is off, it continues to `fallback_url`, which
+/// carries the `nodetect` marker so the server falls back to User-Agent
+/// heuristics instead of serving this page again. `nonce` lets its inline
+/// style and script pass the Content-Security-Policy.
+pub fn generate_screen_detection_html(fallback_url: &str, nonce: &str) -> String {
+    let fallback = serde_json::to_string(fallback_url)
+        .unwrap_or_else(|_| "\"/\"".to_string())
+        .replace('<', "\\u003c");
+    let fallback_attr = fallback_url.replace('&', "&amp;").replace('"', "&quot;");
+    format!(r#"<!DOCTYPE html>
 <html>
 <head>
     <meta charset="UTF-8">
     <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <style>body{margin:0;padding:0;}</style>
-    <script>
-    (function(){
-        var d={
+    <noscript><meta http-equiv="refresh" content="0;url={fallback_attr}"></noscript>
+    <style nonce="{nonce}">body{{margin:0;padding:0;}}</style>
+    <script nonce="{nonce}">
+    (function(){{
+        var d={{
             width:screen.width,
             height:screen.height,
             dpr:window.devicePixelRatio||1,
             viewport_width:window.innerWidth||document.documentElement.clientWidth,
             viewport_height:window.innerHeight||document.documentElement.clientHeight
-        };
+        }};
         document.cookie='screen_info='+encodeURIComponent(JSON.stringify(d))+';path=/;max-age=31536000;SameSite=Lax';
-        location.reload();
-    })();
+        if(document.cookie.indexOf('screen_info=')!==-1){{
+            location.reload();
+        }}else{{
+            location.replace({fallback});
+        }}
+    }})();
     </script>
 </head>
-<body></body>
-</html>"#.to_string()
-}
\ No newline at end of file
+<body><p><a href="{fallback_attr}">Continue</a></p></body>
+</html>"#)
+}
+
+/// URL of the current request with the `nodetect` marker appended
+pub fn screen_detection_fallback_url(req: &HttpRequest) -> String {
+    let query = req.query_string();
+    if query.is_empty() {
+        format!("{}?{}", req.path(), NO_DETECT_PARAM)
+    } else {
+        format!("{}?{}&{}", req.path(), query, NO_DETECT_PARAM)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const BROWSER: &str = "Mozilla/5.0 (X11; Linux x86_64) Gecko/20100101 Firefox/130.0";
+
+    #[test]
+    fn parses_client_hints() {
+        let req = TestRequest::default()
+            .insert_header(("Sec-CH-Viewport-Width", "1280"))
+            .insert_header(("Viewport-Width", "800"))
+            .insert_header(("DPR", " 2.5 "))
+            .insert_header(("Sec-CH-UA-Mobile", "?1"))
+            .insert_header(("Save-Data", "On"))
+            .to_http_request();
+        let hints = parse_client_hints(&req);
+        // The Sec-CH- name wins over the legacy one
+        assert_eq!(hints.viewport_width, Some(1280));
+        assert_eq!(hints.dpr, Some(2.5));
+        assert_eq!(hints.mobile, Some(true));
+        assert!(hints.save_data);
+
+        let req = TestRequest::default()
+            .insert_header(("Viewport-Width", "800"))
+            .insert_header(("Sec-CH-DPR", "wide"))
+            .insert_header(("Sec-CH-UA-Mobile", "?0"))
+            .to_http_request();
+        let hints = parse_client_hints(&req);
+        assert_eq!(hints.viewport_width, Some(800));
+        assert_eq!(hints.dpr, None);
+        assert_eq!(hints.mobile, Some(false));
+        assert!(!hints.save_data);
+
+        let hints = parse_client_hints(&TestRequest::default()
+            .insert_header(("Sec-CH-UA-Mobile", "1"))
+            .insert_header(("Save-Data", "off"))
+            .to_http_request());
+        assert_eq!(hints.viewport_width, None);
+        assert_eq!(hints.mobile, None);
+        assert!(!hints.save_data);
+    }
+
+    #[test]
+    fn detection_page_only_without_viewport() {
+        let browser = || TestRequest::with_uri("/de").insert_header(("User-Agent", BROWSER));
+        assert!(needs_screen_detection(&browser().to_http_request()));
+
+        // Bots get the page straight away
+        let bot = TestRequest::with_uri("/de")
+            .insert_header(("User-Agent", "Mozilla/5.0 (compatible; Googlebot/2.1)"))
+            .to_http_request();
+        assert!(!needs_screen_detection(&bot));
+
+        // Client hints or the cookie already carry the viewport
+        let hinted = browser().insert_header(("Sec-CH-Viewport-Width", "1024")).to_http_request();
+        assert!(!needs_screen_detection(&hinted));
+        let cookie = r#"theme=dark; screen_info=%7B%22width%22%3A1920%2C%22height%22%3A1080%2C%22dpr%22%3A1%2C%22viewport_width%22%3A1600%2C%22viewport_height%22%3A900%7D"#;
+        let cookied = browser().insert_header(("Cookie", cookie)).to_http_request();
+        assert_eq!(parse_screen_info(&cookied).map(|s| s.viewport_width), Some(1600));
+        assert!(!needs_screen_detection(&cookied));
+
+        // A broken cookie does not count
+        let broken = browser().insert_header(("Cookie", "screen_info=%7Bnope")).to_http_request();
+        assert!(needs_screen_detection(&broken));
+
+        // The page gave up and sent us back with the marker
+        let gave_up = TestRequest::with_uri("/de?page=2&nodetect=1")
+            .insert_header(("User-Agent", BROWSER))
+            .to_http_request();
+        assert!(!needs_screen_detection(&gave_up));
+        let similar = TestRequest::with_uri("/de?nodetect=10")
+            .insert_header(("User-Agent", BROWSER))
+            .to_http_request();
+        assert!(needs_screen_detection(&similar));
+    }
+
+    #[test]
+    fn fallback_url_appends_marker() {
+        let req = TestRequest::with_uri("/de/about").to_http_request();
+        assert_eq!(screen_detection_fallback_url(&req), "/de/about?nodetect=1");
+        let req = TestRequest::with_uri("/de/about?tab=cv").to_http_request();
+        assert_eq!(screen_detection_fallback_url(&req), "/de/about?tab=cv&nodetect=1");
+    }
+}
@@ -0,0 +1,306 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::Error;
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::info;
+use std::collections::{BTreeSet, HashSet};
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Files below the static root that compression did not shrink, as
+/// `<ext> <mtime in ns> <path>` lines; hidden, so it is never served
+const SKIPPED_FILE: &str = ".precompress-skipped";
+
+/// Extensions worth compressing; images and fonts like woff2 already are
+const COMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "css", "js", "mjs", "map", "svg", "html", "txt", "json", "xml", "ttf", "otf", "ico",
+];
+
+/// Whether a file type benefits from compression
+pub fn is_compressible(path: &Path) -> bool {
+    path.extension()
+        .and_then(|s| s.to_str())
+        .map(|ext| COMPRESSIBLE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Write `.br` and `.gz` siblings for every compressible file below `root`
+///
+/// Siblings that are newer than their source are left alone, and a sibling
+/// is only kept if it is actually smaller. Files it would not shrink are
+/// noted in [`SKIPPED_FILE`] and not tried again until they change.
+/// Returns the number written.
+pub fn precompress(root: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let root = Path::new(root);
+    let state = root.join(SKIPPED_FILE);
+    let mut run = Run {
+        root,
+        previous: std::fs::read_to_string(&state)
+            .map(|skipped| skipped.lines().map(String::from).collect())
+            .unwrap_or_default(),
+        skipped: BTreeSet::new(),
+        written: 0,
+    };
+    run.walk(root)?;
+
+    if !run.skipped.is_empty() {
+        let lines: Vec<&str> = run.skipped.iter().map(String::as_str).collect();
+        std::fs::write(&state, lines.join("\n") + "\n")?;
+    } else if state.exists() {
+        std::fs::remove_file(&state)?;
+    }
+    Ok(run.written)
+}
+
+/// One `precompress` pass
+struct Run<'a> {
+    root: &'a Path,
+    /// Skipped entries of the last run
+    previous: HashSet<String>,
+    /// Skipped entries of this run, only for files still unchanged
+    skipped: BTreeSet<String>,
+    written: usize,
+}
+
+impl Run<'_> {
+    fn walk(&mut self, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.walk(&path)?;
+            } else if is_compressible(&path) {
+                self.compress(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn compress(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let modified = std::fs::metadata(path)?.modified()?;
+        let stamp = modified.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+        let relative = path.strip_prefix(self.root).unwrap_or(path).display().to_string();
+        let mut source = None;
+
+        for ext in ["br", "gz"] {
+            let target = sibling(path, ext);
+            let fresh = std::fs::metadata(&target)
+                .and_then(|m| m.modified())
+                .map(|t| t >= modified)
+                .unwrap_or(false);
+            if fresh {
+                continue;
+            }
+            let skip = format!("{} {} {}", ext, stamp, relative);
+            if self.previous.contains(&skip) {
+                self.skipped.insert(skip);
+                continue;
+            }
+
+            let source = match &mut source {
+                Some(source) => source,
+                None => source.insert(std::fs::read(path)?),
+            };
+            let compressed = match ext {
+                "br" => brotli(source)?,
+                _ => gzip(source)?,
+            };
+
+            if compressed.len() < source.len() {
+                std::fs::write(&target, compressed)?;
+                info!("Wrote {}", target.display());
+                self.written += 1;
+            } else {
+                if target.exists() {
+                    std::fs::remove_file(&target)?;
+                }
+                self.skipped.insert(skip);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `css/base.css` -> `css/base.css.br`
+fn sibling(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+fn brotli(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    {
+        let mut writer = CompressorWriter::new(&mut out, 4096, 11, 22);
+        writer.write_all(data)?;
+    }
+    Ok(out)
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Middleware wrapped just outside `Compress` to tidy up after it
+///
+/// `Content-Encoding: identity` only keeps `Compress` away from a response
+/// and is dropped before it goes out. An encoded response gets a weak
+/// `ETag`: the strong one `NamedFile` set names the bytes of the file, not
+/// of the compressed body, and the identity response must not share it.
+/// `NamedFile` compares `If-None-Match` weakly, so revalidation still ends
+/// in `304`. `Vary` values set by handlers and by `Compress` are merged
+/// into a single header without duplicates.
+pub fn tidy_headers<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let response = srv.call(req);
+    async move {
+        let mut response = response.await?;
+        let headers = response.headers_mut();
+
+        let identity = headers.get(header::CONTENT_ENCODING)
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"identity"));
+        if identity {
+            headers.remove(header::CONTENT_ENCODING);
+        } else if headers.contains_key(header::CONTENT_ENCODING) {
+            let strong = headers.get(header::ETAG)
+                .filter(|etag| etag.as_bytes().starts_with(b"\""))
+                .and_then(|etag| HeaderValue::from_bytes(&[b"W/", etag.as_bytes()].concat()).ok());
+            if let Some(weak) = strong {
+                headers.insert(header::ETAG, weak);
+            }
+        }
+
+        let mut vary: Vec<String> = Vec::new();
+        for value in headers.get_all(header::VARY).filter_map(|value| value.to_str().ok()) {
+            for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                if !vary.iter().any(|seen| seen.eq_ignore_ascii_case(name)) {
+                    vary.push(name.to_string());
+                }
+            }
+        }
+        if headers.get_all(header::VARY).count() > 1 {
+            if let Ok(value) = HeaderValue::from_str(&vary.join(", ")) {
+                headers.insert(header::VARY, value);
+            }
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{middleware, web, App, HttpResponse};
+
+    #[actix_web::test]
+    async fn drops_identity_marker_and_merges_vary() {
+        let app = init_service(
+            App::new()
+                .wrap(middleware::Compress::default())
+                .wrap_fn(tidy_headers)
+                .route("/plain", web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .insert_header((header::CONTENT_ENCODING, "identity"))
+                        .insert_header((header::VARY, "Accept-Encoding"))
+                        .body("x".repeat(2048))
+                }))
+                .route("/compressed", web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .insert_header((header::VARY, "Accept-Encoding"))
+                        .body("x".repeat(2048))
+                })),
+        ).await;
+
+        let request = |path| TestRequest::with_uri(path)
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_request();
+
+        let plain = call_service(&app, request("/plain")).await;
+        assert!(!plain.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(plain.headers().get_all(header::VARY).count(), 1);
+
+        let compressed = call_service(&app, request("/compressed")).await;
+        assert_eq!(compressed.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        let vary: Vec<_> = compressed.headers().get_all(header::VARY).collect();
+        assert_eq!(vary, [HeaderValue::from_static("Accept-Encoding")]);
+    }
+
+    #[actix_web::test]
+    async fn encoded_responses_get_their_own_etag() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .wrap(middleware::Compress::default())
+                .wrap_fn(tidy_headers)
+                .route("/static/{filename:.*}", web::get().to(crate::libs::static_files::serve)),
+        ).await;
+
+        let request = |encoding| TestRequest::with_uri("/static/css/base.css")
+            .insert_header((header::ACCEPT_ENCODING, encoding));
+        let etag = |headers: &header::HeaderMap| headers.get(header::ETAG).unwrap().to_str().unwrap().to_string();
+
+        let identity = call_service(&app, request("identity").to_request()).await;
+        assert!(!identity.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(etag(identity.headers()).starts_with('"'), "{}", etag(identity.headers()));
+
+        // Compressed on the fly, or from a `.br` sibling if one was written
+        let br = call_service(&app, request("br").to_request()).await;
+        assert_eq!(br.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+        assert!(etag(br.headers()).starts_with("W/\""), "{}", etag(br.headers()));
+        assert_ne!(etag(br.headers()), etag(identity.headers()));
+
+        // The weak tag still revalidates
+        let revalidate = request("br").insert_header((header::IF_NONE_MATCH, etag(br.headers()))).to_request();
+        assert_eq!(call_service(&app, revalidate).await.status(), 304);
+    }
+
+    #[test]
+    fn skips_unchanged_files_compression_did_not_shrink() {
+        let root = std::env::temp_dir().join(format!("vvoss-precompress-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("css")).unwrap();
+        let file = root.join("css/tiny.css");
+        std::fs::write(&file, "a").unwrap();
+        let modified = std::fs::metadata(&file).unwrap().modified().unwrap();
+
+        assert_eq!(precompress(root.to_str().unwrap()).unwrap(), 0);
+        let skipped = std::fs::read_to_string(root.join(SKIPPED_FILE)).unwrap();
+        assert_eq!(skipped.lines().count(), 2, "{}", skipped);
+        assert!(skipped.lines().all(|line| line.ends_with(" css/tiny.css")), "{}", skipped);
+
+        // Now compressible, but with the old mtime it is not looked at again
+        std::fs::write(&file, "a".repeat(4096)).unwrap();
+        std::fs::File::options().write(true).open(&file).unwrap().set_modified(modified).unwrap();
+        assert_eq!(precompress(root.to_str().unwrap()).unwrap(), 0);
+        assert!(!sibling(&file, "br").exists());
+
+        // A changed file is compressed and leaves the list
+        std::fs::File::options().write(true).open(&file).unwrap()
+            .set_modified(modified + std::time::Duration::from_secs(1)).unwrap();
+        assert_eq!(precompress(root.to_str().unwrap()).unwrap(), 2);
+        assert!(sibling(&file, "br").exists());
+        assert!(!root.join(SKIPPED_FILE).exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// State for the repository's config, compressing files of any size
+    fn test_state() -> crate::libs::state::State {
+        use crate::libs::state::{Snapshot, Source, State};
+        let source = Source {
+            config_path: Some("config.toml".to_string()),
+            overrides: vec![
+                ("auth.enabled".to_string(), "false".to_string()),
+                ("static.compress_min_size".to_string(), "0".to_string()),
+            ],
+        };
+        State::new(Snapshot::load(&source).unwrap(), source)
+    }
+}
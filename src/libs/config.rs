@@ -1,36 +1,918 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use super::proxy::TrustedProxies;
+
+/// Prefix for environment overrides, e.g. `VVOSS_SERVER__WORKERS=8`
+const ENV_PREFIX: &str = "VVOSS";
+
+/// Keys given as comma-separated lists in the environment and on the
+/// command line
+const LIST_KEYS: [&str; 5] = [
+    "languages.available",
+    "server.bind",
+    "server.trusted_proxies",
+    "logging.headers",
+    "logging.redact",
+];
+
+/// Arrays of tables, given as TOML values in the environment and on the
+/// command line, e.g. `VVOSS_AUTH__RULES='[{ path = "/static", access = "public" }]'`
+const TABLE_KEYS: [&str; 4] = ["auth.users", "auth.rules", "rate_limit.routes", "security.routes"];
 
 #[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    #[allow(dead_code)]
     pub auth: AuthConfig,
     pub server: ServerConfig,
+    pub templates: TemplatesConfig,
+    #[serde(rename = "static")]
+    pub static_files: StaticConfig,
+    pub logging: LoggingConfig,
     pub languages: LanguagesConfig,
+    #[serde(default)]
+    pub content: ContentConfig,
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+    #[serde(default)]
+    pub reload: ReloadConfig,
+    #[serde(default)]
+    pub status: StatusConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
 }
 
-#[allow(dead_code)]
 #[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct AuthConfig {
     pub enabled: bool,
+    /// Optional htpasswd file with `user:hash` lines (bcrypt or argon2)
+    pub htpasswd: Option<String>,
+    /// `[[auth.users]]` entries, or a `[auth.users]` table of username to hash
+    #[serde(default, deserialize_with = "users")]
+    pub users: Vec<UserConfig>,
+    /// Access for paths not matched by any rule
+    #[serde(default)]
+    pub default: Access,
+    #[serde(default)]
+    pub rules: Vec<AuthRule>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
     pub username: String,
-    pub password: String,
+    /// PHC-format argon2 hash or bcrypt hash, never a plaintext password
+    pub password_hash: String,
+}
+
+/// Accept `[[auth.users]]` entries as well as a `[auth.users]` table of
+/// `username = hash`, which the environment can extend one user at a time
+fn users<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<UserConfig>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Users {
+        List(Vec<UserConfig>),
+        Table(BTreeMap<String, String>),
+    }
+
+    match Users::deserialize(deserializer) {
+        Ok(Users::List(list)) => Ok(list),
+        Ok(Users::Table(table)) => Ok(table.into_iter()
+            .map(|(username, password_hash)| UserConfig { username, password_hash })
+            .collect()),
+        Err(_) => Err(serde::de::Error::custom(
+            "auth.users must be [[auth.users]] entries with username and password_hash, \
+            or a table of username = hash",
+        )),
+    }
 }
 
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Public,
+    #[default]
+    Protected,
+}
+
+/// Access rule for a path prefix; `{...}` segments match any single segment
 #[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AuthRule {
+    pub path: String,
+    pub access: Access,
+    /// Restrict a protected prefix to these users (empty = any valid user)
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ServerConfig {
+    /// Unix socket to listen on; empty disables it
     pub socket_path: String,
+    /// Octal permissions of the Unix socket, e.g. `"0660"`; world-writable
+    /// `"0666"` by default, as before the option existed
+    #[serde(default = "default_socket_mode")]
+    pub socket_mode: String,
+    /// User name or uid to own the Unix socket
+    #[serde(default)]
+    pub socket_owner: Option<String>,
+    /// Group name or gid to own the Unix socket
+    #[serde(default)]
+    pub socket_group: Option<String>,
+    /// TCP addresses to listen on as well, e.g. `"127.0.0.1:8080"`
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub bind: Vec<String>,
+    /// Peers whose `forwarded_header` is believed: addresses, CIDR ranges,
+    /// or `unix` for the Unix socket
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub trusted_proxies: Vec<String>,
+    /// The one header the trusted proxies set; the others are ignored, as
+    /// clients can send them through unchanged
+    #[serde(default)]
+    pub forwarded_header: ForwardedHeader,
+    /// Expect a PROXY protocol v1/v2 header on every site connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Worker threads, defaults to the number of physical CPUs
+    #[serde(default)]
+    pub workers: Option<usize>,
+    /// Seconds to drain in-flight requests after SIGTERM
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+}
+
+/// Header carrying the client address from a trusted proxy
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// RFC 7239 `Forwarded: for=...`
+    Forwarded,
+    /// nginx's `proxy_add_x_forwarded_for`
+    #[default]
+    XForwardedFor,
+    /// A single address, as set by `proxy_set_header X-Real-IP`
+    XRealIp,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_socket_mode() -> String {
+    "0666".to_string()
+}
+
+/// Accept a single string as well as a list of them
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) if one.is_empty() => Vec::new(),
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TemplatesConfig {
+    /// Root directory searched for `**/*.tera`
+    pub path: String,
+    /// Re-read templates on every render when disabled (development)
+    pub cache: bool,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Default filter, e.g. `info` or `vvoss_web=debug`; `RUST_LOG` wins
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Request headers added to access log lines
+    #[serde(default = "default_log_headers")]
+    pub headers: Vec<String>,
+    /// Headers logged as `[redacted]` instead of their value
+    #[serde(default = "default_redact")]
+    pub redact: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines with `key=value` fields
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+    /// Access log in Combined Log Format, other lines as text
+    Combined,
+}
+
+fn default_log_headers() -> Vec<String> {
+    vec!["referer".to_string(), "user-agent".to_string()]
+}
+
+fn default_redact() -> Vec<String> {
+    ["authorization", "cookie", "proxy-authorization", "set-cookie"].map(String::from).to_vec()
 }
 
 #[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct LanguagesConfig {
+    /// URL codes of the languages served; the first is the default
     pub available: Vec<String>,
+    /// Settings per URL code; unset values default to the code itself,
+    /// except the locale, see [`resolve_locales`](Self::resolve_locales)
+    #[serde(default)]
+    pub locales: HashMap<String, LocaleConfig>,
+    /// Render keys without a string as `[missing: key]` instead of the key
+    /// itself (development)
+    #[serde(default)]
+    pub mark_missing: bool,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LocaleConfig {
+    /// Locale of the language's strings, e.g. `de-DE`
+    pub locale: Option<String>,
+    /// Value of `<html lang>`
+    pub html_lang: Option<String>,
+    /// Name shown in the language switcher, in the language itself
+    pub name: Option<String>,
+    #[serde(default)]
+    pub dir: TextDirection,
+    /// URL codes whose strings fill in keys missing here, in order
+    #[serde(default)]
+    pub fallback: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TextDirection {
+    #[default]
+    Ltr,
+    Rtl,
+}
+
+/// A served language with all defaults filled in, as passed to templates
+#[derive(Serialize, Clone)]
+pub struct Locale {
+    pub code: String,
+    pub locale: String,
+    pub html_lang: String,
+    pub name: String,
+    pub dir: TextDirection,
+    pub fallback: Vec<String>,
+}
+
+impl LanguagesConfig {
+    /// Language for requests that do not ask for one
+    pub fn default_code(&self) -> &str {
+        // `load` rejects an empty list
+        &self.available[0]
+    }
+
+    /// Settings for `code`; keys set through the environment are
+    /// lowercased, so the lookup ignores case
+    pub fn locale(&self, code: &str) -> Locale {
+        let config = self.locales.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(code))
+            .map(|(_, config)| config.clone())
+            .unwrap_or_default();
+        Locale {
+            code: code.to_string(),
+            locale: config.locale.unwrap_or_else(|| code.to_string()),
+            html_lang: config.html_lang.unwrap_or_else(|| code.to_string()),
+            name: config.name.unwrap_or_else(|| code.to_string()),
+            dir: config.dir,
+            fallback: config.fallback,
+        }
+    }
+
+    /// Fill in the locale of languages that do not set one from the locales
+    /// that have strings: the code itself if it is one, otherwise the first,
+    /// alphabetically, whose primary subtag is the code, so `de` gets `de-DE`
+    pub fn resolve_locales<'a>(&mut self, known: impl IntoIterator<Item = &'a str>) {
+        let mut known: Vec<&str> = known.into_iter().collect();
+        known.sort_unstable();
+
+        for code in &self.available {
+            let key = self.locales.keys().find(|key| key.eq_ignore_ascii_case(code)).cloned();
+            let config = self.locales.entry(key.unwrap_or_else(|| code.clone())).or_default();
+            if config.locale.is_some() || known.contains(&code.as_str()) {
+                continue;
+            }
+            config.locale = known.iter()
+                .find(|locale| locale.split('-').next().is_some_and(|primary| primary.eq_ignore_ascii_case(code)))
+                .map(|locale| locale.to_string());
+        }
+    }
+
+    /// All served languages, in configured order
+    pub fn locales(&self) -> Vec<Locale> {
+        self.available.iter().map(|code| self.locale(code)).collect()
+    }
+
+    /// `code` followed by its fallbacks, each language once
+    pub fn chain(&self, code: &str) -> Vec<Locale> {
+        let first = self.locale(code);
+        let mut chain = vec![first.clone()];
+        for fallback in &first.fallback {
+            if !chain.iter().any(|l| &l.code == fallback) {
+                chain.push(self.locale(fallback));
+            }
+        }
+        chain
+    }
+
+    /// Require at least one language and fallbacks among the served ones
+    pub fn validate(&self) -> Result<(), String> {
+        if self.available.is_empty() {
+            return Err("languages.available must list at least one language".to_string());
+        }
+        for locale in self.locales() {
+            if let Some(unknown) = locale.fallback.iter().find(|f| !self.available.contains(f)) {
+                return Err(format!("languages.locales.{}: fallback `{}` is not in languages.available", locale.code, unknown));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StaticConfig {
+    /// Root directory for `/static/*`
+    pub path: String,
+    /// `Cache-Control: max-age` in seconds
+    pub cache_max_age: u32,
+    /// Files smaller than this are never compressed on the fly
+    #[serde(default = "default_compress_min_size")]
+    pub compress_min_size: u64,
+}
+
+fn default_compress_min_size() -> u64 {
+    1024
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ContentConfig {
+    /// Root directory of the Markdown content
+    #[serde(default = "default_content_path")]
+    pub path: String,
+}
+
+impl Default for ContentConfig {
+    fn default() -> Self {
+        ContentConfig { path: default_content_path() }
+    }
+}
+
+fn default_content_path() -> String {
+    "content".to_string()
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PrivacyConfig {
+    /// Never set or read cookies; language comes from the URL prefix and
+    /// breakpoints from client hints or CSS media queries
+    #[serde(default)]
+    pub cookieless: bool,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ReloadConfig {
+    /// Poll config, templates, content and static files and reload on
+    /// change (development); SIGHUP always triggers a reload
+    #[serde(default)]
+    pub watch: bool,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StatusConfig {
+    /// Bearer token for `/status`; without one the endpoint is disabled
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Path of the Prometheus scrape endpoint
+    #[serde(default = "default_metrics_path")]
+    pub path: String,
+    /// Serve metrics on their own listener instead of the site's, either
+    /// a TCP address or a Unix socket path; empty = use the site listeners
+    #[serde(default)]
+    pub bind: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig { enabled: true, path: default_metrics_path(), bind: String::new() }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_metrics_path() -> String {
+    "/metrics".to_string()
+}
+
+/// Token buckets per client address, and lockout after failed logins
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Requests per second refilled into each client's bucket; 0 disables
+    /// the site-wide limit
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+    /// Bucket size, i.e. requests allowed in a burst
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+    /// Stricter limits for path prefixes, on top of the site-wide one
+    #[serde(default)]
+    pub routes: Vec<RouteLimit>,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            rate: default_rate(),
+            burst: default_burst(),
+            routes: Vec::new(),
+            lockout: LockoutConfig::default(),
+        }
+    }
+}
+
+fn default_rate() -> f64 {
+    20.0
+}
+
+fn default_burst() -> u32 {
+    100
+}
+
+/// Limit for a path prefix; `{...}` segments match any single segment
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLimit {
+    pub path: String,
+    pub rate: f64,
+    pub burst: u32,
+}
+
+/// Progressive lockout: after `after_failures` failed logins a client is
+/// locked out for `initial` seconds, doubling with every further failure
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LockoutConfig {
+    /// Failed logins before the first lockout; 0 disables lockouts
+    #[serde(default = "default_after_failures")]
+    pub after_failures: u32,
+    /// First lockout in seconds
+    #[serde(default = "default_lockout_initial")]
+    pub initial: u64,
+    /// Longest lockout in seconds
+    #[serde(default = "default_lockout_max")]
+    pub max: u64,
+    /// Seconds without failures after which the count starts over
+    #[serde(default = "default_lockout_reset")]
+    pub reset_after: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            after_failures: default_after_failures(),
+            initial: default_lockout_initial(),
+            max: default_lockout_max(),
+            reset_after: default_lockout_reset(),
+        }
+    }
+}
+
+fn default_after_failures() -> u32 {
+    5
+}
+
+fn default_lockout_initial() -> u64 {
+    60
+}
+
+fn default_lockout_max() -> u64 {
+    3600
+}
+
+fn default_lockout_reset() -> u64 {
+    900
+}
+
+impl RateLimitConfig {
+    /// Reject limits that would block every request
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rate < 0.0 || (self.rate > 0.0 && self.burst == 0) {
+            return Err("rate_limit: rate must be >= 0 and burst >= 1".to_string());
+        }
+        for route in &self.routes {
+            if route.rate <= 0.0 || route.burst == 0 {
+                return Err(format!("rate_limit: route {} needs rate > 0 and burst >= 1", route.path));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Security headers added to every response; an empty value omits the header
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    pub enabled: bool,
+    /// `{nonce}` is replaced with the per-request nonce, also available to
+    /// templates as `csp_nonce`
+    pub content_security_policy: String,
+    pub strict_transport_security: String,
+    pub content_type_options: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    pub frame_options: String,
+    /// Overrides for path prefixes, the most specific match wins
+    pub routes: Vec<SecurityRoute>,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        SecurityConfig {
+            enabled: true,
+            content_security_policy: "default-src 'self'; script-src 'self' 'nonce-{nonce}'; \
+                style-src 'self' 'nonce-{nonce}'; img-src 'self' data:; font-src 'self'; \
+                object-src 'none'; base-uri 'none'; form-action 'self'; frame-ancestors 'none'"
+                .to_string(),
+            strict_transport_security: "max-age=31536000; includeSubDomains".to_string(),
+            content_type_options: "nosniff".to_string(),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            permissions_policy: "camera=(), microphone=(), geolocation=(), payment=(), usb=()".to_string(),
+            frame_options: "DENY".to_string(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+/// Headers replaced for one path prefix; unset ones keep the site-wide value
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SecurityRoute {
+    pub path: String,
+    pub content_security_policy: Option<String>,
+    pub strict_transport_security: Option<String>,
+    pub content_type_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+    pub frame_options: Option<String>,
+}
+
+impl MetricsConfig {
+    /// Separate metrics listener, if configured
+    pub fn listener(&self) -> Option<&str> {
+        Some(self.bind.as_str()).filter(|bind| self.enabled && !bind.is_empty())
+    }
+
+    /// Whether the scrape endpoint is mounted on the site listeners
+    pub fn on_site(&self) -> bool {
+        self.enabled && self.bind.is_empty()
+    }
+}
+
+impl ServerConfig {
+    /// Unix socket path, unless disabled with an empty `socket_path`
+    pub fn socket(&self) -> Option<&str> {
+        Some(self.socket_path.as_str()).filter(|path| !path.is_empty())
+    }
+
+    /// Configured worker threads, or what actix picks by default
+    pub fn worker_count(&self) -> usize {
+        self.workers.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+
+    /// `socket_mode` parsed as octal
+    pub fn socket_permissions(&self) -> Result<u32, String> {
+        u32::from_str_radix(self.socket_mode.trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| format!("invalid server.socket_mode `{}`, expected octal like \"0660\"", self.socket_mode))
+    }
+}
+
+impl TemplatesConfig {
+    /// Glob handed to Tera
+    pub fn glob(&self) -> String {
+        format!("{}/**/*.tera", self.path.trim_end_matches('/'))
+    }
+
+    /// Translation files (`strings.csv`, `<locale>.toml`, `<locale>.ftl`)
+    /// live next to the templates
+    pub fn translations_dir(&self) -> String {
+        format!("{}/translations", self.path.trim_end_matches('/'))
+    }
+}
+
+impl ContentConfig {
+    /// Knowledge base articles, `<lang>/*.md` below it
+    pub fn knowledge_dir(&self) -> String {
+        format!("{}/knowledge", self.path.trim_end_matches('/'))
+    }
 }
 
 impl Config {
-    /// Load config from file
+    /// Load config from file, with environment overrides applied
     pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let config_str = std::fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&config_str)?;
+        Self::load(Some(path), &[])
+    }
+
+    /// Merge defaults, the config file, `VVOSS_` environment variables and
+    /// `key=value` overrides from the command line, later layers winning
+    ///
+    /// Without an explicit `path`, `config.toml` is optional. Nested keys are
+    /// separated by `__` in the environment (`VVOSS_AUTH__USERS__VVOSS=...`)
+    /// and by `.` on the command line (`--set server.workers=8`). Keys from
+    /// the environment are lowercased by config-rs, keys from the file and
+    /// the command line keep their case. Arrays of tables such as
+    /// `auth.rules` take a TOML value in both places.
+    pub fn load(path: Option<&str>, overrides: &[(String, String)]) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load_with_env(path, std::env::vars(), overrides)
+    }
+
+    /// [`load`](Self::load) with the environment given as `vars`
+    fn load_with_env(
+        path: Option<&str>,
+        vars: impl IntoIterator<Item = (String, String)>,
+        overrides: &[(String, String)],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        use ::config::{Environment, File, FileFormat};
+
+        // Every key lives in a section, so only VVOSS_<SECTION>__<KEY> is an
+        // override; other VVOSS_ variables, like the build provenance cargo
+        // exports to `cargo run`, are left alone
+        let prefix = format!("{}_", ENV_PREFIX).to_lowercase();
+        let mut env = ::config::Map::new();
+        let mut tables = Vec::new();
+        for (name, value) in vars {
+            let key = name.to_lowercase();
+            let key = match key.strip_prefix(&prefix) {
+                Some(key) if key.contains("__") => key.replace("__", "."),
+                _ => continue,
+            };
+            if TABLE_KEYS.contains(&key.as_str()) {
+                tables.push((key, value));
+            } else {
+                env.insert(name, value);
+            }
+        }
+        tables.sort();
+
+        let mut environment = Environment::with_prefix(ENV_PREFIX)
+            .prefix_separator("_")
+            .separator("__")
+            .try_parsing(true)
+            .list_separator(",")
+            .source(Some(env));
+        for key in LIST_KEYS {
+            environment = environment.with_list_parse_key(key);
+        }
+
+        let mut builder = ::config::Config::builder()
+            .set_default("auth.enabled", true)?
+            .set_default("server.socket_path", "/var/run/sockets/vvoss_www.sock")?
+            .set_default("templates.path", "templates")?
+            .set_default("templates.cache", true)?
+            .set_default("static.path", "static")?
+            .set_default("static.cache_max_age", 3600)?
+            .set_default("logging.level", "info")?
+            .set_default("languages.available", vec!["de", "en"])?
+            .add_source(
+                File::new(path.unwrap_or("config.toml"), FileFormat::Toml)
+                    .required(path.is_some()),
+            )
+            .add_source(environment);
+
+        // Arrays of tables, from the environment and then the command line,
+        // each as a TOML snippet of its own
+        let table_overrides = overrides.iter().filter(|(key, _)| TABLE_KEYS.contains(&key.as_str()));
+        for (key, value) in tables.iter().chain(table_overrides) {
+            let (section, name) = key.split_once('.').unwrap_or(("", key));
+            let snippet = format!("[{}]\n{} = {}", section, name, value);
+            toml::from_str::<toml::Table>(&snippet)
+                .map_err(|e| format!("{}: expected a TOML array or table, {}", key, e.message()))?;
+            builder = builder.add_source(File::from_str(&snippet, FileFormat::Toml));
+        }
+
+        for (key, value) in overrides.iter().filter(|(key, _)| !TABLE_KEYS.contains(&key.as_str())) {
+            builder = if LIST_KEYS.contains(&key.as_str()) {
+                builder.set_override(key.as_str(), value.split(',').collect::<Vec<_>>())?
+            } else {
+                builder.set_override(key.as_str(), value.as_str())?
+            };
+        }
+
+        let config: Config = builder.build()?.try_deserialize()?;
+        config.server.socket_permissions()?;
+        TrustedProxies::parse(&config.server.trusted_proxies, config.server.forwarded_header)?;
+        config.rate_limit.validate()?;
+        config.languages.validate()?;
         Ok(config)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `contents` to a config file of its own and return its path
+    fn config_file(name: &str, contents: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("vvoss-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.toml", name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        // The error names the key and what was meant instead
+        for (contents, key, suggested) in [
+            ("[server]\nsocket_path = \"\"\nworker = 4\n", "worker", "workers"),
+            ("[static]\npath = \"static\"\ncache_max_age = 60\nmax_age = 60\n", "max_age", "cache_max_age"),
+            ("[stats]\ntoken = \"x\"\n", "stats", "status"),
+        ] {
+            let err = Config::from_file(&config_file(key, contents)).err().unwrap().to_string();
+            assert!(err.contains(&format!("unknown field `{}`", key)), "{}", err);
+            assert!(err.contains(&format!("`{}`", suggested)), "{}", err);
+        }
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let path = config_file("layers", "\
+            [server]\nsocket_path = \"\"\nworkers = 2\n\
+            [static]\npath = \"static\"\ncache_max_age = 60\n\
+            [logging]\nlevel = \"warn\"\n");
+        let env = vars(&[
+            ("VVOSS_SERVER__WORKERS", "4"),
+            ("VVOSS_LOGGING__LEVEL", "debug"),
+            ("vvoss_templates__cache", "false"),
+            // Not VVOSS_<SECTION>__<KEY>, so not an override
+            ("VVOSS_GIT_COMMIT", "0123abc"),
+            ("OTHER_SERVER__WORKERS", "16"),
+        ]);
+        let overrides = vec![("server.workers".to_string(), "8".to_string())];
+
+        let config = Config::load_with_env(Some(&path), env.clone(), &overrides).unwrap();
+        assert_eq!(config.templates.path, "templates");
+        assert_eq!(config.server.socket_permissions(), Ok(0o666));
+        assert_eq!(config.static_files.cache_max_age, 60);
+        assert_eq!(config.logging.level, "debug");
+        assert!(!config.templates.cache);
+        assert_eq!(config.server.workers, Some(8));
+
+        let config = Config::load_with_env(Some(&path), env, &[]).unwrap();
+        assert_eq!(config.server.workers, Some(4));
+        let config = Config::load_with_env(Some(&path), Vec::new(), &[]).unwrap();
+        assert_eq!(config.server.workers, Some(2));
+        assert_eq!(config.logging.level, "warn");
+        assert!(config.templates.cache);
+
+        // Only an explicitly given file has to exist
+        let missing = format!("{}.missing", path);
+        assert!(Config::load_with_env(Some(&missing), Vec::new(), &[]).is_err());
+    }
+
+    #[test]
+    fn list_keys_split_on_commas() {
+        let path = config_file("lists", "[server]\nsocket_path = \"\"\nbind = \"127.0.0.1:9000\"\n");
+        let config = Config::load_with_env(Some(&path), Vec::new(), &[]).unwrap();
+        assert_eq!(config.server.bind, ["127.0.0.1:9000"]);
+
+        let env = vars(&[("VVOSS_SERVER__BIND", "127.0.0.1:8080,127.0.0.1:8081")]);
+        let overrides = vec![("languages.available".to_string(), "en,de".to_string())];
+        let config = Config::load_with_env(Some(&path), env, &overrides).unwrap();
+        assert_eq!(config.server.bind, ["127.0.0.1:8080", "127.0.0.1:8081"]);
+        assert_eq!(config.languages.available, ["en", "de"]);
+    }
+
+    #[test]
+    fn arrays_of_tables_take_toml() {
+        let path = config_file("tables", "\
+            [server]\nsocket_path = \"\"\n\
+            [[auth.rules]]\npath = \"/static\"\naccess = \"public\"\n");
+        let env = vars(&[(
+            "VVOSS_AUTH__RULES",
+            r#"[{ path = "/healthz", access = "public" }, { path = "/de/cv", access = "protected", users = ["vvoss"] }]"#,
+        )]);
+        let overrides = vec![(
+            "rate_limit.routes".to_string(),
+            r#"[{ path = "/de/cv", rate = 1.0, burst = 5 }]"#.to_string(),
+        )];
+
+        let config = Config::load_with_env(Some(&path), env.clone(), &overrides).unwrap();
+        let rules: Vec<&str> = config.auth.rules.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(rules, ["/healthz", "/de/cv"]);
+        assert_eq!(config.auth.rules[1].users, ["vvoss"]);
+        assert_eq!(config.rate_limit.routes[0].burst, 5);
+
+        // The command line beats the environment
+        let overrides = vec![("auth.rules".to_string(), "[]".to_string())];
+        let config = Config::load_with_env(Some(&path), env, &overrides).unwrap();
+        assert!(config.auth.rules.is_empty());
+
+        let overrides = vec![("auth.rules".to_string(), "[{ path = ".to_string())];
+        let err = Config::load_with_env(Some(&path), Vec::new(), &overrides).err().unwrap().to_string();
+        assert!(err.starts_with("auth.rules: expected a TOML array or table"), "{}", err);
+    }
+
+    #[test]
+    fn users_as_list_or_table() {
+        let names = |config: &Config| {
+            let mut names: Vec<String> = config.auth.users.iter().map(|u| u.username.clone()).collect();
+            names.sort();
+            names
+        };
+
+        let list = config_file("users-list", "\
+            [server]\nsocket_path = \"\"\n\
+            [[auth.users]]\nusername = \"Alice\"\npassword_hash = \"$2b$04$hash\"\n");
+        let config = Config::load_with_env(Some(&list), Vec::new(), &[]).unwrap();
+        assert_eq!(names(&config), ["Alice"]);
+        assert_eq!(config.auth.users[0].password_hash, "$2b$04$hash");
+
+        let table = config_file("users-table", "\
+            [server]\nsocket_path = \"\"\n\
+            [auth.users]\nAlice = \"$2b$04$hash\"\n");
+        let env = vars(&[("VVOSS_AUTH__USERS__BOB", "$2b$04$other")]);
+        let config = Config::load_with_env(Some(&table), env, &[]).unwrap();
+        assert_eq!(names(&config), ["Alice", "bob"]);
+
+        // A whole list from the environment keeps the case of its names
+        let env = vars(&[("VVOSS_AUTH__USERS", r#"[{ username = "Carol", password_hash = "$2b$04$x" }]"#)]);
+        let config = Config::load_with_env(Some(&table), env, &[]).unwrap();
+        assert_eq!(names(&config), ["Carol"]);
+
+        let env = vars(&[("VVOSS_AUTH__USERS", r#"[{ username = "Carol" }]"#)]);
+        assert!(Config::load_with_env(Some(&table), env, &[]).is_err());
+    }
+
+    #[test]
+    fn unset_locales_follow_the_strings() {
+        let mut languages: LanguagesConfig = toml::from_str(r#"
+            available = ["de", "en", "fr", "EL"]
+            [locales.fr]
+            name = "Français"
+            [locales.en]
+            locale = "en-GB"
+        "#).unwrap();
+        languages.resolve_locales(["en-EN", "fr-FR", "fr-CA", "de", "de-DE", "el-GR"]);
+
+        let locale = |code: &str| languages.locale(code).locale;
+        assert_eq!(locale("de"), "de");
+        assert_eq!(locale("en"), "en-GB");
+        assert_eq!(locale("fr"), "fr-CA");
+        assert_eq!(languages.locale("fr").name, "Français");
+        assert_eq!(locale("EL"), "el-GR");
+
+        // Without any strings the code itself is looked for, and missed
+        let mut languages: LanguagesConfig = toml::from_str(r#"available = ["it"]"#).unwrap();
+        languages.resolve_locales(["de-DE"]);
+        assert_eq!(languages.locale("it").locale, "it");
+    }
+}
@@ -1,264 +1,159 @@
 use actix_web::{web, HttpRequest, HttpResponse, Result};
 use tera::{Tera, Context};
-use std::path::Path;
 use chrono::Datelike;
+use log::debug;
+use std::time::Instant;
 
-use super::client::{detect_client_info, parse_screen_info, is_bot_request, generate_screen_detection_html};
-use super::translations::Translations;
+use super::accept_language;
+use super::client::{
+    append_client_hint_headers, detect_client_info, generate_screen_detection_html, needs_screen_detection,
+    screen_detection_fallback_url,
+};
 use super::config::Config;
+use super::metrics::metrics;
+use super::security;
+use super::state::{Snapshot, State};
+use super::translations;
+use super::build_info::BUILD_INFO;
 
-/// Generic page handler with language from URL
-pub async fn render_page_with_lang(
-    req: HttpRequest,
-    tmpl: web::Data<Tera>,
-    translations: web::Data<Translations>,
-    config: web::Data<Config>,
-    template_path: &str,
-    current_page: &str,
-    lang: &str,
-) -> Result<HttpResponse> {
-    render_with_lang(req, tmpl, translations, config, template_path, current_page, lang).await
+/// Render a template in `lang`, re-reading all templates first when
+/// caching is off
+fn render_template(tmpl: &Tera, config: &Config, template_name: &str, lang: &str, context: &Context) -> Result<String> {
+    let started = Instant::now();
+    let rendered = translations::with_language(lang, || {
+        if config.templates.cache {
+            tmpl.render(template_name, context)
+        } else {
+            let mut fresh = tmpl.clone();
+            fresh.full_reload().and_then(|_| fresh.render(template_name, context))
+        }
+    });
+    metrics().observe_render(template_name, started.elapsed());
+    rendered.map_err(actix_web::error::ErrorInternalServerError)
 }
 
-/// Generic page handler - DRY principle (deprecated, kept for compatibility)
-pub async fn render_page(
-    req: HttpRequest,
-    tmpl: web::Data<Tera>,
-    translations: web::Data<Translations>,
-    config: web::Data<Config>,
-    template_path: &str,
-    current_page: &str,
-) -> Result<HttpResponse> {
-    render_with_client_detection(req, tmpl, translations, config, template_path, current_page).await
+/// Serve the screen-detection page if needed, counting how often it is
+/// served versus skipped
+fn screen_detection_response(req: &HttpRequest, cookieless: bool) -> Option<HttpResponse> {
+    if cookieless {
+        metrics().screen_detection("disabled");
+        return None;
+    }
+    if !needs_screen_detection(req) {
+        metrics().screen_detection("skipped");
+        return None;
+    }
+
+    metrics().screen_detection("served");
+    let mut response = HttpResponse::Ok();
+    append_client_hint_headers(&mut response);
+    Some(response
+        .insert_header(("Cache-Control", "no-store"))
+        .content_type("text/html")
+        .body(generate_screen_detection_html(&screen_detection_fallback_url(req), &security::nonce(req))))
 }
 
-/// Render page with client detection
-pub async fn render_with_client_detection(
+/// Generic page handler with language from URL
+pub async fn render_page_with_lang(
     req: HttpRequest,
-    tmpl: web::Data<Tera>,
-    translations: web::Data<Translations>,
-    config: web::Data<Config>,
-    template_name: &str,
+    state: &Snapshot,
+    template_path: &str,
     current_page: &str,
+    lang: &str,
+    context: Context,
 ) -> Result<HttpResponse> {
-    // Skip detection for bots
-    if !is_bot_request(&req) && parse_screen_info(&req).is_none() {
-        return Ok(HttpResponse::Ok()
-            .content_type("text/html")
-            .body(generate_screen_detection_html()));
-    }
-    
-    let mut client = detect_client_info(&req);
-    
-    // Check for language cookie first
-    let mut cookie_lang = None;
-    if let Some(cookie_header) = req.headers().get("cookie") {
-        if let Ok(cookies_str) = cookie_header.to_str() {
-            for cookie in cookies_str.split(';') {
-                let trimmed = cookie.trim();
-                if let Some(value) = trimmed.strip_prefix("lang=") {
-                    let lang = value.to_lowercase();
-                    if config.languages.available.contains(&lang) {
-                        cookie_lang = Some(lang);
-                        break;
-                    }
-                }
-            }
-        }
-    }
-    
-    // Check for language override in query parameters (overrides cookie)
-    let query_string = req.query_string();
-    let mut selected_lang = None;
-    
-    // Parse query parameters manually
-    for param in query_string.split('&') {
-        if let Some(value) = param.strip_prefix("lang=") {
-            let lang = value.to_lowercase();
-            if config.languages.available.contains(&lang) {
-                selected_lang = Some(lang);
-                break;
-            }
-        }
-    }
-    
-    // Priority: 1. Query param, 2. Cookie, 3. Browser detection
-    client.lang = if let Some(lang) = selected_lang.clone() {
-        lang
-    } else if let Some(lang) = cookie_lang {
-        lang
-    } else {
-        let lang_code = client.language[..2].to_lowercase();
-        if config.languages.available.contains(&lang_code) {
-            lang_code
-        } else {
-            config.languages.available.first().unwrap_or(&"en".to_string()).clone()
-        }
-    };
-    
-    // Get latest update date from git log or use current date as fallback
-    let latest_update = std::process::Command::new("git")
-        .args(&["log", "-1", "--format=%cd", "--date=short"])
-        .output()
-        .ok()
-        .and_then(|output| String::from_utf8(output.stdout).ok())
-        .map(|s| s.trim().to_string())
-        .unwrap_or_else(|| chrono::Local::now().format("%Y-%m-%d").to_string());
-    
-    // Create page info object
-    let page_info = serde_json::json!({
-        "languages": config.languages.available.clone(),
-        "latest_update": latest_update
-    });
-    
-    let mut context = Context::new();
-    context.insert("current_year", &chrono::Local::now().year());
-    context.insert("client", &client);
-    context.insert("page", &page_info);
-    context.insert("current_page", &current_page);
-    
-    // Create a simple translation map for the detected locale
-    let locale_key = if client.lang == "de" { "de-DE" } else { "en-EN" };
-    let t = translations.strings.get(locale_key)
-        .or_else(|| translations.strings.get("en-EN"))
-        .cloned()
-        .unwrap_or_default();
-    context.insert("t", &t);
-
-    let rendered = tmpl
-        .render(template_name, &context)
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
-
-    // Build response with optional language cookie
-    let mut response = HttpResponse::Ok();
-    
-    // If language was explicitly selected via query param, set a cookie
-    if selected_lang.is_some() {
-        response.cookie(
-            actix_web::cookie::Cookie::build("lang", client.lang.clone())
-                .path("/")
-                .max_age(actix_web::cookie::time::Duration::days(365))
-                .same_site(actix_web::cookie::SameSite::Lax)
-                .finish()
-        );
-    }
-    
-    Ok(response.content_type("text/html").body(rendered))
+    render_with_lang(req, state, template_path, current_page, lang, context).await
 }
 
 /// Render page with language from URL
+///
+/// `context` carries page-specific variables and is extended with the
+/// common ones (client, page, translations).
 pub async fn render_with_lang(
     req: HttpRequest,
-    tmpl: web::Data<Tera>,
-    translations: web::Data<Translations>,
-    config: web::Data<Config>,
+    state: &Snapshot,
     template_name: &str,
     current_page: &str,
     lang: &str,
+    mut context: Context,
 ) -> Result<HttpResponse> {
-    // Skip detection for bots
-    if !is_bot_request(&req) && parse_screen_info(&req).is_none() {
-        return Ok(HttpResponse::Ok()
-            .content_type("text/html")
-            .body(generate_screen_detection_html()));
+    let (config, translations) = (&state.config, &state.translations);
+    let cookieless = config.privacy.cookieless;
+
+    // Fall back to the detection page only without client hints or cookie
+    if let Some(response) = screen_detection_response(&req, cookieless) {
+        return Ok(response);
     }
     
-    let mut client = detect_client_info(&req);
+    let mut client = detect_client_info(&req, cookieless, &config.languages);
     
     // Use language from URL
     client.lang = lang.to_string();
     
-    // Get latest update date from git log or use current date as fallback
-    let latest_update = std::process::Command::new("git")
-        .args(&["log", "-1", "--format=%cd", "--date=short"])
-        .output()
-        .ok()
-        .and_then(|output| String::from_utf8(output.stdout).ok())
-        .map(|s| s.trim().to_string())
-        .unwrap_or_else(|| chrono::Local::now().format("%Y-%m-%d").to_string());
-    
     // Create page info object
     let page_info = serde_json::json!({
         "languages": config.languages.available.clone(),
-        "latest_update": latest_update
+        "locales": config.languages.locales(),
+        "latest_update": BUILD_INFO.latest_update(),
+        "build": BUILD_INFO
     });
     
-    let mut context = Context::new();
     context.insert("current_year", &chrono::Local::now().year());
     context.insert("client", &client);
     context.insert("page", &page_info);
     context.insert("current_page", &current_page);
     context.insert("current_lang", &lang);
+    context.insert("csp_nonce", &security::nonce(&req));
     
-    // Create a simple translation map for the detected locale
-    let locale_key = if lang == "de" { "de-DE" } else { "en-EN" };
-    let t = translations.strings.get(locale_key)
-        .or_else(|| translations.strings.get("en-EN"))
-        .cloned()
-        .unwrap_or_default();
+    // Strings of the language, gaps filled from its fallback chain
+    let chain = config.languages.chain(lang);
+    let t = translations.merged(chain.iter().map(|locale| locale.locale.as_str()));
     context.insert("t", &t);
+    context.insert("locale", &chain[0]);
 
-    let rendered = tmpl
-        .render(template_name, &context)
-        .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+    let rendered = render_template(&state.tera, config, template_name, lang, &context)?;
+
+    let mut response = HttpResponse::Ok();
+    append_client_hint_headers(&mut response);
 
     // Set language cookie
-    Ok(HttpResponse::Ok()
-        .cookie(
+    if !cookieless {
+        response.cookie(
             actix_web::cookie::Cookie::build("lang", lang.to_string())
                 .path("/")
                 .max_age(actix_web::cookie::time::Duration::days(365))
                 .same_site(actix_web::cookie::SameSite::Lax)
                 .finish()
-        )
-        .content_type("text/html")
-        .body(rendered))
-}
+        );
+    }
 
-/// Serve static files (CSS, JS, images, fonts)
-pub async fn static_files(path: web::Path<String>) -> Result<HttpResponse> {
-    let file_path = format!("static/{}", path);
-    
-    // Determine content type based on extension
-    let content_type = match Path::new(&file_path).extension().and_then(|s| s.to_str()) {
-        Some("css") => "text/css",
-        Some("js") => "application/javascript",
-        Some("png") => "image/png",
-        Some("jpg") | Some("jpeg") => "image/jpeg",
-        Some("gif") => "image/gif",
-        Some("svg") => "image/svg+xml",
-        Some("ico") => "image/x-icon",
-        Some("woff") => "font/woff",
-        Some("woff2") => "font/woff2",
-        Some("ttf") => "font/ttf",
-        Some("otf") => "font/otf",
-        _ => "application/octet-stream",
-    };
+    Ok(response.content_type("text/html").body(rendered))
+}
 
-    match std::fs::read(&file_path) {
-        Ok(contents) => Ok(HttpResponse::Ok()
-            .content_type(content_type)
-            .body(contents)),
-        Err(_) => Ok(HttpResponse::NotFound().finish()),
-    }
+/// Build provenance as JSON
+pub async fn version() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(BUILD_INFO))
 }
 
 // Redirect to language-specific URL
 pub async fn redirect_to_language(
     req: HttpRequest,
-    config: web::Data<Config>,
+    state: web::Data<State>,
 ) -> Result<HttpResponse> {
+    let state = state.current();
+    let config = &state.config;
+
     // Check for language cookie
-    let mut lang = "en".to_string(); // Default
+    let mut lang = None;
     
-    if let Some(cookie_header) = req.headers().get("cookie") {
+    if let Some(cookie_header) = req.headers().get("cookie").filter(|_| !config.privacy.cookieless) {
         if let Ok(cookies_str) = cookie_header.to_str() {
             for cookie in cookies_str.split(';') {
                 let trimmed = cookie.trim();
                 if let Some(value) = trimmed.strip_prefix("lang=") {
                     let cookie_lang = value.to_lowercase();
                     if config.languages.available.contains(&cookie_lang) {
-                        lang = cookie_lang;
+                        lang = Some(cookie_lang);
                         break;
                     }
                 }
@@ -266,21 +161,14 @@ pub async fn redirect_to_language(
         }
     }
     
-    // If no cookie, detect from browser
-    if lang == "en" {
-        if let Some(accept_lang) = req.headers().get("accept-language") {
-            if let Ok(lang_str) = accept_lang.to_str() {
-                for lang_part in lang_str.split(',') {
-                    let detected = lang_part.split(';').next().unwrap_or("").trim();
-                    let lang_code = detected[..2.min(detected.len())].to_lowercase();
-                    if config.languages.available.contains(&lang_code) {
-                        lang = lang_code;
-                        break;
-                    }
-                }
-            }
-        }
-    }
+    // If no cookie, detect from browser, then fall back to the default
+    let lang = lang
+        .or_else(|| {
+            let negotiated = accept_language::from_request(&req, &config.languages.available)?;
+            debug!("Accept-Language range {} selected {}", negotiated.range, negotiated.language);
+            Some(negotiated.language.to_string())
+        })
+        .unwrap_or_else(|| config.languages.default_code().to_string());
     
     // Get the current path
     let path = req.path();
@@ -298,17 +186,17 @@ macro_rules! page_handler {
         pub async fn $name(
             req: HttpRequest,
             lang: web::Path<String>,
-            tmpl: web::Data<Tera>,
-            translations: web::Data<Translations>,
-            config: web::Data<Config>,
+            state: web::Data<State>,
         ) -> Result<HttpResponse> {
+            let state = state.current();
+
             // Validate language
             let lang_str = lang.into_inner();
-            if !config.languages.available.contains(&lang_str) {
+            if !state.config.languages.available.contains(&lang_str) {
                 return Ok(HttpResponse::NotFound().finish());
             }
             
-            render_page_with_lang(req, tmpl, translations, config, $template, $page_name, &lang_str).await
+            render_page_with_lang(req, &state, $template, $page_name, &lang_str, Context::new()).await
         }
     };
 }
@@ -316,5 +204,45 @@ macro_rules! page_handler {
 // Generate all page handlers
 page_handler!(index, "content/index.tera", "index");
 page_handler!(portfolio, "content/portfolio.tera", "portfolio");
-page_handler!(knowledge, "content/knowledge.tera", "knowledge");
-page_handler!(impressum, "content/impressum.tera", "impressum");
\ No newline at end of file
+page_handler!(impressum, "content/impressum.tera", "impressum");
+
+/// Knowledge base index, listing all published articles
+pub async fn knowledge(
+    req: HttpRequest,
+    lang: web::Path<String>,
+    state: web::Data<State>,
+) -> Result<HttpResponse> {
+    let state = state.current();
+    let lang_str = lang.into_inner();
+    if !state.config.languages.available.contains(&lang_str) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let mut context = Context::new();
+    context.insert("articles", state.knowledge.list(&lang_str));
+
+    render_page_with_lang(req, &state, "content/knowledge.tera", "knowledge", &lang_str, context).await
+}
+
+/// Single knowledge base article rendered from Markdown
+pub async fn knowledge_article(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    state: web::Data<State>,
+) -> Result<HttpResponse> {
+    let state = state.current();
+    let (lang_str, slug) = path.into_inner();
+    if !state.config.languages.available.contains(&lang_str) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let article = match state.knowledge.get(&lang_str, &slug) {
+        Some(article) => article,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let mut context = Context::new();
+    context.insert("article", article);
+
+    render_page_with_lang(req, &state, "content/article.tera", "knowledge", &lang_str, context).await
+}
@@ -0,0 +1,169 @@
+use log::{error, info, warn};
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// First inherited descriptor in the systemd socket activation protocol
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Set on a process started by an upgrade; it stops the old one once ready
+const UPGRADE_PARENT_ENV: &str = "UPGRADE_PARENT_PID";
+
+/// Name systemd gives descriptors without a `FileDescriptorName=`
+const UNNAMED: &str = "unknown";
+
+/// What systemd or the process we take over from handed us
+pub struct Handoff {
+    /// Listener descriptors from `LISTEN_FDS`, named by `LISTEN_FDNAMES`
+    pub fds: Vec<(RawFd, String)>,
+    /// Process that started us through [`upgrade`]
+    parent: Option<libc::pid_t>,
+}
+
+impl Handoff {
+    /// Read the hand-off variables and remove them so that children do not
+    /// pick them up
+    ///
+    /// Must run before the runtime starts its threads, since changing the
+    /// environment while other threads may read it is a data race.
+    /// Follows systemd socket activation: `LISTEN_PID` must be our pid, so
+    /// that descriptors meant for a parent are not taken by mistake. Only
+    /// an upgrade leaves it unset, because the child pid is not known
+    /// before exec; it names our parent in `UPGRADE_PARENT_PID` instead.
+    pub fn from_env() -> Self {
+        let count = std::env::var("LISTEN_FDS").ok().and_then(|v| v.parse::<RawFd>().ok());
+        let listen_pid = std::env::var("LISTEN_PID").ok();
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        let mut names = names.split(':');
+        // Anyone else named there is not ours to stop
+        let parent = std::env::var(UPGRADE_PARENT_ENV).ok()
+            .and_then(|p| p.parse::<libc::pid_t>().ok())
+            .filter(|pid| *pid == unsafe { libc::getppid() });
+        let pid_matches = for_us(listen_pid.as_deref(), parent.is_some(), std::process::id());
+
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDNAMES");
+        std::env::remove_var(UPGRADE_PARENT_ENV);
+
+        let fds = match count {
+            Some(count) if pid_matches && count > 0 => {
+                (LISTEN_FDS_START..LISTEN_FDS_START + count)
+                    .map(|fd| {
+                        // Inherited descriptors must not leak into later children
+                        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+                        (fd, names.next().filter(|name| !name.is_empty()).unwrap_or(UNNAMED).to_string())
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        Handoff { fds, parent }
+    }
+
+    /// Tell the process we were upgraded from to drain and exit
+    pub fn notify_parent(&self) {
+        if let Some(pid) = self.parent {
+            info!("Taking over from pid {}, asking it to shut down", pid);
+            if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+                warn!("Could not signal pid {}: {}", pid, std::io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+/// Whether inherited descriptors are meant for process `pid`: `LISTEN_PID`
+/// must name it, or be absent for a child started by [`upgrade`]
+fn for_us(listen_pid: Option<&str>, upgraded: bool, pid: u32) -> bool {
+    match listen_pid {
+        Some(listen_pid) => listen_pid.parse() == Ok(pid),
+        None => upgraded,
+    }
+}
+
+/// Start a new copy of this binary that inherits `fds` as `LISTEN_FDS`,
+/// named in `LISTEN_FDNAMES`
+///
+/// The new process serves on the same sockets and sends us SIGTERM once it
+/// is ready, so we drain in-flight requests without a gap in between.
+pub fn upgrade(named: &[(RawFd, String)]) -> std::io::Result<u32> {
+    let mut args = std::env::args();
+    let program = args.next().unwrap_or_else(|| "vvoss-web".to_string());
+    let fds: Vec<RawFd> = named.iter().map(|(fd, _)| *fd).collect();
+    let names: Vec<&str> = named.iter().map(|(_, name)| name.as_str()).collect();
+    let count = fds.len() as RawFd;
+
+    let mut command = Command::new(&program);
+    command
+        .args(args)
+        .env("LISTEN_FDS", count.to_string())
+        .env("LISTEN_FDNAMES", names.join(":"))
+        .env_remove("LISTEN_PID")
+        .env(UPGRADE_PARENT_ENV, std::process::id().to_string());
+
+    // The closure runs between fork and exec, where only async-signal-safe
+    // calls are allowed; allocating could deadlock on the allocator lock
+    let mut moved: Vec<RawFd> = vec![-1; fds.len()];
+
+    unsafe {
+        command.pre_exec(move || {
+            // Move the sockets out of the way first so that placing them at
+            // 3.. cannot clobber one that is still to be moved
+            for (fd, tmp) in fds.iter().zip(moved.iter_mut()) {
+                *tmp = libc::fcntl(*fd, libc::F_DUPFD, LISTEN_FDS_START + count);
+                if *tmp < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            for (i, tmp) in moved.iter().enumerate() {
+                // dup2 clears FD_CLOEXEC on the target, so it survives exec
+                if libc::dup2(*tmp, LISTEN_FDS_START + i as RawFd) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                libc::close(*tmp);
+            }
+            Ok(())
+        });
+    }
+
+    let child = command.spawn()?;
+    Ok(child.id())
+}
+
+/// Upgrade to a fresh binary whenever SIGUSR2 arrives
+pub fn spawn_upgrade_on_sigusr2(fds: Vec<(RawFd, String)>) {
+    actix_web::rt::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut usr2 = match signal(SignalKind::user_defined2()) {
+            Ok(usr2) => usr2,
+            Err(e) => {
+                error!("Cannot listen for SIGUSR2: {}", e);
+                return;
+            }
+        };
+        while usr2.recv().await.is_some() {
+            match upgrade(&fds) {
+                Ok(pid) => info!("SIGUSR2 received, started new process {}", pid),
+                Err(e) => error!("Upgrade failed, keeping this process: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptors_must_be_meant_for_us() {
+        assert!(for_us(Some("42"), false, 42));
+        assert!(for_us(Some("42"), true, 42));
+        assert!(!for_us(Some("41"), false, 42));
+        assert!(!for_us(Some("41"), true, 42));
+        assert!(!for_us(Some(""), false, 42));
+        // Without LISTEN_PID only an upgrade from our parent qualifies
+        assert!(!for_us(None, false, 42));
+        assert!(for_us(None, true, 42));
+    }
+}
@@ -0,0 +1,74 @@
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Result};
+
+use super::build_info::BUILD_INFO;
+use super::state::State;
+
+/// Probe endpoints, public unless an auth rule says otherwise
+pub const PATHS: [&str; 3] = ["/healthz", "/readyz", "/status"];
+
+/// Liveness: the process is up and answering
+pub async fn healthz() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body("ok"))
+}
+
+/// Readiness: config, templates and translations are loaded and the
+/// listeners are accepting
+pub async fn readyz(state: web::Data<State>) -> Result<HttpResponse> {
+    let snapshot = state.current();
+    let checks = serde_json::json!({
+        "listening": state.is_ready(),
+        "templates": snapshot.tera.get_template_names().next().is_some(),
+        "translations": !snapshot.translations.strings.is_empty(),
+    });
+    let ready = checks.as_object()
+        .map(|checks| checks.values().all(|ok| ok.as_bool() == Some(true)))
+        .unwrap_or(false);
+
+    let mut response = if ready { HttpResponse::Ok() } else { HttpResponse::ServiceUnavailable() };
+    Ok(response
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(serde_json::json!({ "ready": ready, "checks": checks })))
+}
+
+/// Runtime details for operators
+///
+/// Requires `Authorization: Bearer <status.token>`; without a token set
+/// the endpoint is refused to everyone. The connection peer is no use
+/// here, behind a proxy on loopback every client would look local.
+pub async fn status(req: HttpRequest, state: web::Data<State>) -> Result<HttpResponse> {
+    let snapshot = state.current();
+
+    let allowed = snapshot.config.status.token.as_ref().is_some_and(|token| {
+        req.headers().get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+    });
+    if !allowed {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let mut languages: Vec<&String> = snapshot.translations.strings.keys().collect();
+    languages.sort();
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(serde_json::json!({
+            "ready": state.is_ready(),
+            "uptime_seconds": state.uptime().as_secs(),
+            "build": BUILD_INFO,
+            "languages": {
+                "available": snapshot.config.languages.available,
+                "translations": languages,
+            },
+            "templates": snapshot.tera.get_template_names().count(),
+            "workers": state.workers(),
+        })))
+}
+
+/// Compare secrets without leaking the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
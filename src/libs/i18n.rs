@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use super::config::Config;
+use super::translations::Translations;
+
+/// Suffixes of plural forms, `knowledge.count.one` for `knowledge.count`
+const PLURAL_CATEGORIES: [&str; 6] = ["zero", "one", "two", "few", "many", "other"];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// One problem found by [`check`]
+pub struct Finding {
+    pub severity: Severity,
+    /// `missing`, `fallback`, `duplicate`, `placeholder`, `orphaned`,
+    /// `identical` or `dynamic`
+    pub kind: &'static str,
+    pub locale: Option<String>,
+    pub key: String,
+    pub detail: String,
+}
+
+/// Outcome of `vvoss-web i18n check`
+#[derive(Default)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    pub fn errors(&self) -> usize {
+        self.findings.iter().filter(|f| f.severity == Severity::Error).count()
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.findings.len() - self.errors()
+    }
+
+    /// Process exit status: 1 if there are errors
+    pub fn exit_code(&self) -> i32 {
+        if self.errors() > 0 { 1 } else { 0 }
+    }
+
+    fn push(&mut self, severity: Severity, kind: &'static str, locale: Option<&str>, key: &str, detail: String) {
+        self.findings.push(Finding {
+            severity,
+            kind,
+            locale: locale.map(String::from),
+            key: key.to_string(),
+            detail,
+        });
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for finding in &self.findings {
+            let severity = match finding.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            write!(f, "{}[{}]", severity, finding.kind)?;
+            if let Some(locale) = &finding.locale {
+                write!(f, " {}", locale)?;
+            }
+            writeln!(f, " `{}`: {}", finding.key, finding.detail)?;
+        }
+        write!(f, "{} errors, {} warnings", self.errors(), self.warnings())
+    }
+}
+
+/// Keys looked up in templates, with the first place each is used
+#[derive(Default)]
+struct Usage {
+    keys: BTreeMap<String, String>,
+    /// `file:line` of lookups whose key is not a literal
+    dynamic: Vec<String>,
+}
+
+/// Compare the `t[...]` and `t(key=...)` lookups in the templates with
+/// the translations
+///
+/// Errors: a configured language lacks a used key even through its
+/// fallbacks, a key is defined twice for a locale, or a translation uses
+/// a `{placeholder}` the default language's text does not have. Warnings:
+/// a key only resolves through a fallback, a translation leaves out a
+/// placeholder, a key is never used, or has the same text as in the
+/// default language.
+pub fn check(config: &Config) -> Result<Report, Box<dyn std::error::Error>> {
+    let translations = Translations::from_dir(&config.templates.translations_dir())?;
+    let mut usage = Usage::default();
+    scan_dir(Path::new(&config.templates.path), &mut usage)?;
+
+    let mut report = Report::default();
+    let mut languages = config.languages.clone();
+    languages.resolve_locales(translations.strings.keys().map(String::as_str));
+    let languages = &languages;
+    let locales = languages.locales();
+    let empty = Default::default();
+    let strings = |locale: &str| translations.strings.get(locale).unwrap_or(&empty);
+
+    // `t(key=..., count=...)` may find plural forms instead of the key
+    let defines = |locale: &str, key: &str| {
+        let strings = strings(locale);
+        strings.contains_key(key) || strings.contains_key(&format!("{}.other", key))
+    };
+
+    for (key, used_at) in &usage.keys {
+        for locale in &locales {
+            if defines(&locale.locale, key) {
+                continue;
+            }
+            let chain = languages.chain(&locale.code);
+            match chain.iter().skip(1).find(|fallback| defines(&fallback.locale, key)) {
+                Some(fallback) => report.push(Severity::Warning, "fallback", Some(&locale.locale), key,
+                    format!("missing, {} is used instead (used in {})", fallback.locale, used_at)),
+                None => report.push(Severity::Error, "missing", Some(&locale.locale), key,
+                    format!("used in {}", used_at)),
+            }
+        }
+    }
+
+    for duplicate in &translations.duplicates {
+        report.push(Severity::Error, "duplicate", Some(&duplicate.locale), &duplicate.key,
+            format!("defined in {} and {}", duplicate.first, duplicate.second));
+    }
+
+    // Orphans are reported once per key, naming the locales defining it
+    let mut defined: BTreeMap<&String, Vec<&str>> = BTreeMap::new();
+    for locale in &locales {
+        for key in strings(&locale.locale).keys() {
+            defined.entry(key).or_default().push(&locale.locale);
+        }
+    }
+    for (key, in_locales) in &defined {
+        let plural_of = key.rsplit_once('.')
+            .filter(|(_, category)| PLURAL_CATEGORIES.contains(category))
+            .map(|(base, _)| base);
+        if !usage.keys.contains_key(*key) && !plural_of.is_some_and(|base| usage.keys.contains_key(base)) {
+            report.push(Severity::Warning, "orphaned", None, key,
+                format!("not used by any template (defined for {})", in_locales.join(", ")));
+        }
+    }
+
+    let source = languages.locale(languages.default_code());
+    for locale in locales.iter().filter(|l| l.locale != source.locale) {
+        let mut keys: Vec<(&String, &String)> = strings(&locale.locale).iter().collect();
+        keys.sort();
+        for (key, text) in keys {
+            let Some(original) = strings(&source.locale).get(key) else { continue };
+            let (theirs, ours) = (placeholders(text), placeholders(original));
+            for name in theirs.iter().filter(|name| !ours.contains(name)) {
+                report.push(Severity::Error, "placeholder", Some(&locale.locale), key,
+                    format!("`{{{}}}` is not an argument in {}", name, source.locale));
+            }
+            for name in ours.iter().filter(|name| !theirs.contains(name)) {
+                report.push(Severity::Warning, "placeholder", Some(&locale.locale), key,
+                    format!("`{{{}}}` of {} is left out", name, source.locale));
+            }
+        }
+    }
+
+    for locale in locales.iter().filter(|l| l.locale != source.locale) {
+        let mut identical: Vec<&String> = strings(&locale.locale).iter()
+            .filter(|(key, text)| strings(&source.locale).get(*key) == Some(*text))
+            .map(|(key, _)| key)
+            .collect();
+        identical.sort();
+        for key in identical {
+            report.push(Severity::Warning, "identical", Some(&locale.locale), key,
+                format!("same text as {}", source.locale));
+        }
+    }
+
+    for place in &usage.dynamic {
+        report.push(Severity::Warning, "dynamic", None, "?", format!("lookup with a computed key in {}", place));
+    }
+
+    Ok(report)
+}
+
+/// Names of the `{name}` placeholders in a string, sorted and deduplicated
+fn placeholders(text: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = text.split('{').skip(1)
+        .filter_map(|rest| rest.split_once('}').map(|(name, _)| name))
+        .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_'))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Collect lookups from every `*.tera` file below `dir`
+fn scan_dir(dir: &Path, usage: &mut Usage) -> Result<(), Box<dyn std::error::Error>> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            scan_dir(&path, usage)?;
+        } else if path.extension().and_then(|s| s.to_str()) == Some("tera") {
+            let source = std::fs::read_to_string(&path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            scan(&source, &path.display().to_string(), usage);
+        }
+    }
+    Ok(())
+}
+
+/// Find `t["key"]`, `t['key']` and `t(..., key="key", ...)`; lookups
+/// with any other key expression are dynamic
+fn scan(source: &str, name: &str, usage: &mut Usage) {
+    for (index, line) in source.lines().enumerate() {
+        let place = format!("{}:{}", name, index + 1);
+        let mut offset = 0;
+        while let Some(found) = line[offset..].find(['[', '(']) {
+            let bracket = offset + found;
+            offset = bracket + 1;
+
+            // `t` must be a name of its own, not the end of `list[` or `split(`
+            let Some(before) = line[..bracket].strip_suffix('t') else { continue };
+            if before.chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '.') {
+                continue;
+            }
+
+            let call = line[bracket..].starts_with('(');
+            let after = line[bracket + 1..].trim_start();
+            let expression = match call {
+                true => key_argument(after),
+                false => Some(after),
+            };
+            let literal = expression.and_then(|expression| {
+                let quote = expression.chars().next().filter(|q| *q == '"' || *q == '\'')?;
+                let inner = &expression[1..];
+                let end = inner.find(quote)?;
+                let closing: &[char] = if call { &[',', ')'] } else { &[']'] };
+                inner[end + 1..].trim_start().starts_with(closing).then(|| &inner[..end])
+            });
+            match literal {
+                Some(key) => {
+                    usage.keys.entry(key.to_string()).or_insert_with(|| place.clone());
+                }
+                None => usage.dynamic.push(place.clone()),
+            }
+        }
+    }
+}
+
+/// The expression passed as `key=` to a call, whatever its position among
+/// the named arguments
+fn key_argument(args: &str) -> Option<&str> {
+    fn key(arg: &str) -> Option<&str> {
+        arg.trim_start().strip_prefix("key")
+            .map(str::trim_start)
+            .and_then(|s| s.strip_prefix('='))
+            .filter(|s| !s.starts_with('='))
+            .map(str::trim_start)
+    }
+
+    if let Some(expression) = key(args) {
+        return Some(expression);
+    }
+
+    // Later arguments start after a comma outside of strings and brackets
+    let (mut quote, mut depth) = (None, 0);
+    for (index, c) in args.char_indices() {
+        match c {
+            _ if quote == Some(c) => quote = None,
+            _ if quote.is_some() => {}
+            '"' | '\'' => quote = Some(c),
+            '(' | '[' => depth += 1,
+            ')' | ']' if depth == 0 => return None,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                if let Some(expression) = key(&args[index + 1..]) {
+                    return Some(expression);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_finds_literal_keys() {
+        // Template line, keys it uses and number of dynamic lookups
+        let cases: &[(&str, &[&str], usize)] = &[
+            (r#"{{ t["nav.home"] }}"#, &["nav.home"], 0),
+            ("{{ t['nav.home'] }}", &["nav.home"], 0),
+            (r#"{{ t[ "spaced" ] }}"#, &["spaced"], 0),
+            (r#"{{ t(key="items", count=3) }}"#, &["items"], 0),
+            (r#"{{ t(lang="en", key="items") }}"#, &["items"], 0),
+            (r#"{{ t(lang="a,key=b", count=n, key = 'late') }}"#, &["late"], 0),
+            (r#"{{ t(keys="x", key="real") }}"#, &["real"], 0),
+            (r#"{{ t["a"] }} {{ t(key="b") }}"#, &["a", "b"], 0),
+            // Not lookups: `t` ends another name or is a field
+            (r#"{{ list[0] }} {{ s | split(pat=",") }} {{ format(x) }} {{ page.t["x"] }}"#, &[], 0),
+            ("{{ t[name] }}", &[], 1),
+            (r#"{{ t["a" ~ b] }}"#, &[], 1),
+            ("{{ t(count=2) }}", &[], 1),
+            (r#"{{ t(lang="en") }}"#, &[], 1),
+            (r#"{{ t(key=prefix ~ "x") }}"#, &[], 1),
+        ];
+
+        for (line, keys, dynamic) in cases {
+            let mut usage = Usage::default();
+            scan(line, "page.tera", &mut usage);
+            let found: Vec<&str> = usage.keys.keys().map(String::as_str).collect();
+            assert_eq!(found, *keys, "{}", line);
+            assert_eq!(usage.dynamic.len(), *dynamic, "{}", line);
+        }
+    }
+
+    #[test]
+    fn scan_records_first_use() {
+        let mut usage = Usage::default();
+        scan("{{ t[\"a\"] }}\n{{ t[b] }}\n{{ t(key=\"a\") }}\n", "page.tera", &mut usage);
+        assert_eq!(usage.keys["a"], "page.tera:1");
+        assert_eq!(usage.dynamic, ["page.tera:2"]);
+    }
+}
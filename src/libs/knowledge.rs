@@ -0,0 +1,262 @@
+use pulldown_cmark::{html, Options, Parser};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Front matter at the top of an article, delimited by `+++` lines
+#[derive(Deserialize)]
+struct FrontMatter {
+    title: String,
+    date: Option<toml::value::Datetime>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    summary: String,
+    #[serde(default)]
+    draft: bool,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Article {
+    pub slug: String,
+    pub title: String,
+    pub date: String,
+    pub tags: Vec<String>,
+    pub summary: String,
+    pub html: String,
+}
+
+#[derive(Clone, Default)]
+pub struct Knowledge {
+    pub articles: HashMap<String, Vec<Article>>,
+}
+
+impl Knowledge {
+    /// Load articles from `<root>/<lang>/*.md` for every available language
+    pub fn from_dir(root: &str, languages: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut articles = HashMap::new();
+
+        for lang in languages {
+            let dir = Path::new(root).join(lang);
+            let mut list = Vec::new();
+
+            if dir.is_dir() {
+                for entry in std::fs::read_dir(&dir)? {
+                    let path = entry?.path();
+                    if path.extension().and_then(|s| s.to_str()) != Some("md") {
+                        continue;
+                    }
+
+                    let slug = match path.file_stem().and_then(|s| s.to_str()) {
+                        Some(stem) => stem.to_string(),
+                        None => continue,
+                    };
+
+                    let source = std::fs::read_to_string(&path)?;
+                    let article = parse_article(&slug, &source)
+                        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+                    if let Some(article) = article {
+                        list.push(article);
+                    }
+                }
+            }
+
+            // Newest first, undated articles last
+            list.sort_by(|a, b| b.date.cmp(&a.date).then_with(|| a.slug.cmp(&b.slug)));
+            articles.insert(lang.clone(), list);
+        }
+
+        Ok(Knowledge { articles })
+    }
+
+    /// All published articles for a language
+    pub fn list(&self, lang: &str) -> &[Article] {
+        self.articles.get(lang).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    /// Find a single article by slug
+    pub fn get(&self, lang: &str, slug: &str) -> Option<&Article> {
+        self.list(lang).iter().find(|a| a.slug == slug)
+    }
+}
+
+/// Parse an article, returning `None` for drafts
+fn parse_article(slug: &str, source: &str) -> Result<Option<Article>, Box<dyn std::error::Error>> {
+    let (front, body) = split_front_matter(source)
+        .ok_or("missing +++ front matter")?;
+    let meta: FrontMatter = toml::from_str(front)?;
+
+    if meta.draft {
+        return Ok(None);
+    }
+
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_HEADING_ATTRIBUTES;
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, Parser::new_ext(body, options));
+
+    Ok(Some(Article {
+        slug: slug.to_string(),
+        title: meta.title,
+        date: meta.date.map(|d| d.to_string()).unwrap_or_default(),
+        tags: meta.tags,
+        summary: meta.summary,
+        html: rendered,
+    }))
+}
+
+/// Split `+++\n<toml>\n+++\n<markdown>` into its two parts; the closing
+/// `+++` must be on a line of its own
+fn split_front_matter(source: &str) -> Option<(&str, &str)> {
+    let rest = source.strip_prefix("+++")?;
+    let rest = rest.strip_prefix("\r\n").or_else(|| rest.strip_prefix('\n'))?;
+
+    let mut start = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end_matches(['\r', '\n']) == "+++" {
+            let front = rest[..start].strip_suffix('\n').unwrap_or(&rest[..start]);
+            let front = front.strip_suffix('\r').unwrap_or(front);
+            return Some((front, &rest[start + line.len()..]));
+        }
+        start += line.len();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_front_matter_at_delimiter_line() {
+        let cases: &[(&str, Option<(&str, &str)>)] = &[
+            ("+++\ntitle = \"A\"\n+++\nBody\n", Some(("title = \"A\"", "Body\n"))),
+            ("+++\r\ntitle = \"A\"\r\n+++\r\nBody", Some(("title = \"A\"", "Body"))),
+            ("+++\ntitle = \"A\"\n+++", Some(("title = \"A\"", ""))),
+            ("+++\n+++\nBody", Some(("", "Body"))),
+            // `+++` inside a line neither closes nor breaks the front matter
+            ("+++\ntitle = \"a\\n+++b\"\n+++\nx\n+++ y\n", Some(("title = \"a\\n+++b\"", "x\n+++ y\n"))),
+            ("+++\ntitle = \"A\"\n+++ not closed\n", None),
+            ("+++\ntitle = \"A\"\n++++\n", None),
+            ("title = \"A\"\n+++\n", None),
+        ];
+
+        for (source, expected) in cases {
+            assert_eq!(split_front_matter(source), *expected, "source {:?}", source);
+        }
+    }
+
+    #[test]
+    fn parses_articles() {
+        // Expected title, date and a fragment of the HTML, or `None` for a draft
+        let cases = [
+            (
+                "+++\ntitle = \"Dated\"\ndate = 2024-05-01\ntags = [\"rust\"]\n+++\n# Heading\n",
+                Some(("Dated", "2024-05-01", "<h1>Heading</h1>")),
+            ),
+            ("+++\ntitle = \"Undated\"\n+++\nText\n", Some(("Undated", "", "<p>Text</p>"))),
+            (
+                "+++\ntitle = \"Table\"\n+++\n| a | b |\n|---|---|\n| 1 | 2 |\n",
+                Some(("Table", "", "<td>1</td>")),
+            ),
+            (
+                "+++\ntitle = \"Notes\"\n+++\nClaim[^1]\n\n[^1]: Source\n",
+                Some(("Notes", "", "class=\"footnote-definition\"")),
+            ),
+            ("+++\ntitle = \"Struck\"\n+++\n~~old~~\n", Some(("Struck", "", "<del>old</del>"))),
+            ("+++\ntitle = \"Hidden\"\ndraft = true\n+++\nText\n", None),
+        ];
+
+        for (source, expected) in cases {
+            let article = parse_article("slug", source).unwrap();
+            match (article, expected) {
+                (Some(article), Some((title, date, html))) => {
+                    assert_eq!(article.slug, "slug");
+                    assert_eq!(article.title, title);
+                    assert_eq!(article.date, date);
+                    assert!(article.html.contains(html), "{:?} rendered {}", source, article.html);
+                }
+                (None, None) => {}
+                (article, _) => panic!("{:?} parsed to {:?}", source, article.map(|a| a.title)),
+            }
+        }
+
+        for broken in ["title = \"No delimiters\"\n", "+++\ntitle = \n+++\n", "+++\ndate = 2024-05-01\n+++\n"] {
+            assert!(parse_article("slug", broken).is_err(), "{:?}", broken);
+        }
+    }
+
+    #[test]
+    fn loads_published_articles_newest_first() {
+        let root = std::env::temp_dir().join(format!("vvoss-knowledge-{}", std::process::id()));
+        let dir = root.join("en");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, front) in [
+            ("old.md", "title = \"Old\"\ndate = 2023-01-01"),
+            ("new.md", "title = \"New\"\ndate = 2024-01-01"),
+            ("undated-b.md", "title = \"B\""),
+            ("undated-a.md", "title = \"A\""),
+            ("draft.md", "title = \"Draft\"\ndate = 2025-01-01\ndraft = true"),
+            ("notes.txt", "title = \"Not Markdown\""),
+        ] {
+            std::fs::write(dir.join(name), format!("+++\n{}\n+++\nBody\n", front)).unwrap();
+        }
+        let languages = ["en".to_string(), "de".to_string()];
+
+        let knowledge = Knowledge::from_dir(root.to_str().unwrap(), &languages).unwrap();
+        let slugs: Vec<&str> = knowledge.list("en").iter().map(|a| a.slug.as_str()).collect();
+        assert_eq!(slugs, ["new", "old", "undated-a", "undated-b"]);
+        assert!(knowledge.get("en", "draft").is_none());
+        assert!(knowledge.list("de").is_empty());
+
+        // A broken article names its file
+        std::fs::write(dir.join("broken.md"), "+++\ntitle = \n+++\nBody\n").unwrap();
+        let error = Knowledge::from_dir(root.to_str().unwrap(), &languages).err().unwrap().to_string();
+        assert!(error.starts_with(&dir.join("broken.md").display().to_string()), "{}", error);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn article_route_hides_drafts_and_unknown_slugs() {
+        use crate::libs::routes;
+        use crate::libs::state::{Snapshot, Source, State};
+        use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+        use actix_web::{web, App};
+
+        let root = std::env::temp_dir().join(format!("vvoss-knowledge-route-{}", std::process::id()));
+        let dir = root.join("knowledge/en");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("published.md"), "+++\ntitle = \"Published\"\n+++\nBody\n").unwrap();
+        std::fs::write(dir.join("hidden.md"), "+++\ntitle = \"Hidden\"\ndraft = true\n+++\nBody\n").unwrap();
+
+        let source = Source {
+            config_path: Some("config.toml".to_string()),
+            overrides: vec![
+                ("auth.enabled".to_string(), "false".to_string()),
+                ("content.path".to_string(), root.display().to_string()),
+            ],
+        };
+        let state = State::new(Snapshot::load(&source).unwrap(), source);
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(routes::configure)).await;
+
+        for (uri, status) in [
+            ("/en/knowledge/published", 200),
+            ("/en/knowledge/hidden", 404),
+            ("/en/knowledge/missing", 404),
+            ("/de/knowledge/published", 404),
+            ("/xx/knowledge/published", 404),
+        ] {
+            let req = TestRequest::with_uri(uri).insert_header(("Sec-CH-Viewport-Width", "1280")).to_request();
+            assert_eq!(call_service(&app, req).await.status(), status, "{}", uri);
+        }
+        let req = TestRequest::with_uri("/en/knowledge/published").insert_header(("Sec-CH-Viewport-Width", "1280"));
+        let body = call_and_read_body(&app, req.to_request()).await;
+        assert!(String::from_utf8_lossy(&body).contains("Published"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
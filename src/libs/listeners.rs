@@ -0,0 +1,319 @@
+use actix_http::body::MessageBody;
+use actix_http::error::DispatchError;
+use actix_http::{HttpService, Protocol, Request, Response};
+use actix_server::{Server, ServerBuilder};
+use actix_service::{fn_service, map_config, IntoServiceFactory, ServiceFactory, ServiceFactoryExt};
+use actix_web::dev::AppConfig;
+use log::info;
+use std::ffi::CString;
+use std::fmt;
+use std::future::Future;
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::pin::Pin;
+use std::task::Poll;
+
+use super::config::ServerConfig;
+use super::proxy;
+
+/// `LISTEN_FDNAMES` entry for the site listeners
+pub const SITE: &str = "site";
+
+/// `LISTEN_FDNAMES` entry for the separate metrics listener
+pub const METRICS: &str = "metrics";
+
+/// A socket the server accepts connections on
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    /// Wrap an inherited descriptor, telling Unix and TCP sockets apart
+    ///
+    /// # Safety
+    /// `fd` must be an open listening socket owned by nobody else.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        let unix = UnixListener::from_raw_fd(fd);
+        if unix.local_addr().is_ok() {
+            Listener::Unix(unix)
+        } else {
+            Listener::Tcp(TcpListener::from_raw_fd(unix.into_raw_fd()))
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Listener::Unix(listener) => match listener.local_addr().ok().and_then(|a| a.as_pathname().map(|p| p.display().to_string())) {
+                Some(path) => format!("unix:{}", path),
+                None => "unix socket".to_string(),
+            },
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => format!("http://{}", addr),
+                Err(_) => "tcp socket".to_string(),
+            },
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Unix(listener) => listener.as_raw_fd(),
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// Serve the application built by `factory` on `listener`
+///
+/// Like `HttpServer::listen`, but with `proxy_protocol` the PROXY header is
+/// read off each connection first and its source becomes the peer address.
+pub fn serve<F, I, S, B>(
+    builder: ServerBuilder,
+    listener: Listener,
+    factory: F,
+    proxy_protocol: bool,
+) -> std::io::Result<ServerBuilder>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<Response<actix_http::body::BoxBody>> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    B: MessageBody + 'static,
+{
+    let name = listener.describe();
+    match listener {
+        Listener::Unix(listener) => builder.listen_uds(name, listener, move || {
+            fn_service(move |mut io: tokio::net::UnixStream| async move {
+                let peer = match proxy_protocol {
+                    true => proxy::read_header(&mut io).await.map_err(DispatchError::Io)?,
+                    false => None,
+                };
+                Ok((io, Protocol::Http1, peer))
+            })
+            .and_then(HttpService::build().finish(map_config(factory().into_factory(), app_config)))
+        }),
+        Listener::Tcp(listener) => {
+            let addr = listener.local_addr()?;
+            builder.listen(name, listener, move || {
+                fn_service(move |mut io: tokio::net::TcpStream| async move {
+                    let _ = io.set_nodelay(true);
+                    let peer = match proxy_protocol {
+                        true => proxy::read_header(&mut io).await.map_err(DispatchError::Io)?,
+                        false => io.peer_addr().ok(),
+                    };
+                    Ok((io, Protocol::Http1, peer))
+                })
+                .and_then(HttpService::build().local_addr(addr).finish(map_config(factory().into_factory(), app_config)))
+            })
+        }
+    }
+}
+
+/// Connection config handed to the application
+///
+/// The application only uses the peer address, taken from the connection
+/// or its PROXY header and kept as [`ClientAddr`](proxy::ClientAddr); the
+/// host name and bound address in `AppConfig` go unused, so the default
+/// serves every listener.
+fn app_config(_: ()) -> AppConfig {
+    AppConfig::default()
+}
+
+/// Start `server` without waiting for it to finish
+///
+/// The server future is lazy: its first poll starts the workers, returning
+/// only once each has built its services, and the accept loop after them.
+/// Anything that depends on requests being served, like readiness or
+/// stopping the process we took the sockets over from, has to wait for
+/// this. Returns the result if the server finished right away, e.g. because
+/// a worker failed to start.
+pub async fn start(server: &mut Server) -> Option<std::io::Result<()>> {
+    std::future::poll_fn(|cx| match Pin::new(&mut *server).poll(cx) {
+        Poll::Ready(result) => Poll::Ready(Some(result)),
+        Poll::Pending => Poll::Ready(None),
+    })
+    .await
+}
+
+/// Bind the Unix socket and every `bind` address from `[server]`
+pub fn bind_all(server: &ServerConfig) -> std::io::Result<Vec<Listener>> {
+    let mut listeners = Vec::new();
+
+    if let Some(socket_path) = server.socket() {
+        listeners.push(Listener::Unix(bind_unix(server, socket_path)?));
+    }
+
+    for addr in &server.bind {
+        listeners.push(Listener::Tcp(bind_tcp(addr)?));
+    }
+
+    if listeners.is_empty() {
+        return Err(std::io::Error::other("no listeners configured, set server.socket_path or server.bind"));
+    }
+    Ok(listeners)
+}
+
+/// Bind a single address: a Unix socket if it is a path, TCP otherwise
+///
+/// Unix sockets get the mode and ownership configured in `[server]`.
+pub fn bind_addr(server: &ServerConfig, addr: &str) -> std::io::Result<Listener> {
+    if addr.starts_with('/') || addr.starts_with('.') {
+        Ok(Listener::Unix(bind_unix(server, addr)?))
+    } else {
+        Ok(Listener::Tcp(bind_tcp(addr)?))
+    }
+}
+
+fn bind_tcp(addr: &str) -> std::io::Result<TcpListener> {
+    TcpListener::bind(addr)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("cannot bind {}: {}", addr, e)))
+}
+
+/// Bind the Unix socket, then apply the configured mode and ownership
+fn bind_unix(server: &ServerConfig, socket_path: &str) -> std::io::Result<UnixListener> {
+    remove_stale_socket(socket_path)?;
+
+    let listener = UnixListener::bind(socket_path)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("cannot bind {}: {}", socket_path, e)))?;
+
+    let owner = server.socket_owner.as_deref().map(lookup_user).transpose()?;
+    let group = server.socket_group.as_deref().map(lookup_group).transpose()?;
+    if owner.is_some() || group.is_some() {
+        std::os::unix::fs::chown(socket_path, owner, group)?;
+    }
+
+    let mode = server.socket_permissions().map_err(std::io::Error::other)?;
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(mode))?;
+
+    info!("Socket {} has mode {:04o}", socket_path, mode);
+    Ok(listener)
+}
+
+/// Remove a socket left by a previous run, but never that of a server
+/// still accepting on it; taking over a live one is what SIGUSR2 is for
+fn remove_stale_socket(socket_path: &str) -> std::io::Result<()> {
+    match UnixStream::connect(socket_path) {
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("cannot bind {}: another process is listening on it", socket_path),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => std::fs::remove_file(socket_path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(std::io::Error::new(e.kind(), format!("cannot bind {}: {}", socket_path, e))),
+    }
+}
+
+/// Resolve a user name or numeric uid
+fn lookup_user(user: &str) -> std::io::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(user).map_err(std::io::Error::other)?;
+    // Only called at startup, before any other thread uses getpwnam
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(std::io::Error::other(format!("unknown socket_owner `{}`", user)));
+    }
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+/// Resolve a group name or numeric gid
+fn lookup_group(group: &str) -> std::io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(std::io::Error::other)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(std::io::Error::other(format!("unknown socket_group `{}`", group)));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_service::fn_factory;
+    use actix_web::dev::ServiceRequest;
+    use actix_web::{web, App, HttpResponse};
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A one-worker server answering `ok` on `/`, or failing to start
+    fn server(healthy: bool) -> (Server, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = move || {
+            App::new()
+                .route("/", web::get().to(|| async { "ok" }))
+                .default_service(fn_factory(move || async move {
+                    match healthy {
+                        true => Ok(fn_service(|req: ServiceRequest| async {
+                            Ok::<_, actix_web::Error>(req.into_response(HttpResponse::NotFound()))
+                        })),
+                        false => Err(()),
+                    }
+                }))
+        };
+        let builder = Server::build().workers(1).disable_signals();
+        let server = serve(builder, Listener::Tcp(listener), app, false).unwrap().run();
+        (server, addr)
+    }
+
+    #[actix_web::test]
+    async fn start_returns_once_workers_accept() {
+        let (mut server, addr) = server(true);
+        assert!(start(&mut server).await.is_none());
+
+        // Answered without polling the server again: the workers and the
+        // accept loop run on threads of their own from the first poll on
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("ok"), "{}", response);
+
+        // Stop commands are handled by the server future
+        let stopped = server.handle().stop(true);
+        server.await.unwrap();
+        stopped.await;
+    }
+
+    #[actix_web::test]
+    async fn start_reports_workers_that_fail() {
+        let (mut server, _) = server(false);
+        assert!(matches!(start(&mut server).await, Some(Err(_))));
+    }
+
+    #[test]
+    fn removes_only_stale_sockets() {
+        let dir = std::env::temp_dir().join(format!("vvoss-listeners-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("site.sock");
+        let path = path.to_str().unwrap();
+
+        // Nothing there yet
+        remove_stale_socket(path).unwrap();
+
+        // A live server keeps its socket
+        let live = UnixListener::bind(path).unwrap();
+        let err = remove_stale_socket(path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        assert!(Path::new(path).exists());
+
+        // Once it is gone the file left behind is removed
+        drop(live);
+        remove_stale_socket(path).unwrap();
+        assert!(!Path::new(path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
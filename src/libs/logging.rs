@@ -0,0 +1,482 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::error::BlockingError;
+use actix_web::web::Bytes;
+use actix_web::{web, Error, HttpMessage};
+use log::kv::{self, VisitSource};
+use log::{Level, Record};
+use std::future::Future;
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use super::auth::AuthenticatedUser;
+use super::config::{LogFormat, LoggingConfig};
+use super::proxy::ClientAddr;
+use super::state::State;
+
+/// Target of access log lines
+pub const ACCESS_TARGET: &str = "access";
+
+/// Header carrying the request ID in from nginx and back out to the client
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest request ID accepted from upstream
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Logged in place of redacted header values
+const REDACTED: &str = "[redacted]";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// ID of the request being handled on this task, if any
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Run `f` on the blocking thread pool like `web::block`, keeping the
+/// request ID for the lines it logs; task-locals stay behind on this task
+pub async fn block<F, R>(f: F) -> Result<R, BlockingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let id = request_id();
+    web::block(move || match id {
+        Some(id) => REQUEST_ID.sync_scope(id, f),
+        None => f(),
+    })
+    .await
+}
+
+/// Install the global logger in the configured format
+///
+/// Every line logged while a request is handled carries its request ID.
+/// `RUST_LOG` overrides `logging.level`.
+pub fn init(config: &LoggingConfig) {
+    let format = config.format;
+    env_logger::Builder::from_env(env_logger::Env::new().default_filter_or(config.level.as_str()))
+        .format(move |buf, record| {
+            let mut fields = Fields::default();
+            let _ = record.key_values().visit(&mut fields);
+            let id = request_id();
+
+            match format {
+                LogFormat::Json => {
+                    let mut line = serde_json::Map::new();
+                    line.insert("time".into(), chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true).into());
+                    line.insert("level".into(), record.level().as_str().into());
+                    line.insert("target".into(), record.target().into());
+                    if let Some(id) = id {
+                        line.insert("request_id".into(), id.into());
+                    }
+                    line.insert("message".into(), record.args().to_string().into());
+                    for (key, value) in fields.0 {
+                        line.insert(key, value);
+                    }
+                    writeln!(buf, "{}", serde_json::Value::Object(line))
+                }
+                // The message already is the complete log line
+                LogFormat::Combined if record.target() == ACCESS_TARGET => writeln!(buf, "{}", record.args()),
+                LogFormat::Text | LogFormat::Combined => {
+                    write!(buf, "[{} {:<5} {}]", buf.timestamp(), record.level(), record.target())?;
+                    if let Some(id) = id {
+                        write!(buf, " [{}]", id)?;
+                    }
+                    write!(buf, " {}", escape_control(&record.args().to_string()))?;
+                    for (key, value) in fields.0 {
+                        match value {
+                            // Debug formatting escapes control characters too
+                            serde_json::Value::String(s) if s.is_empty() || s.contains(needs_quotes) => {
+                                write!(buf, " {}={:?}", key, s)?
+                            }
+                            serde_json::Value::String(s) => write!(buf, " {}={}", key, s)?,
+                            other => write!(buf, " {}={}", key, other)?,
+                        }
+                    }
+                    writeln!(buf)
+                }
+            }
+        })
+        .init();
+}
+
+fn needs_quotes(c: char) -> bool {
+    c.is_control() || c == ' ' || c == '"' || c == '='
+}
+
+/// `value` with control characters written as `\xHH`, so that nothing a
+/// client sends can start a new log line
+fn escape_control(value: &str) -> std::borrow::Cow<'_, str> {
+    if value.contains(char::is_control) {
+        escape(value, |c| c.is_control()).into()
+    } else {
+        value.into()
+    }
+}
+
+/// `value` with the characters `special` picks written as `\xHH`
+/// (`\u{HHHH}` beyond ASCII)
+fn escape(value: &str, special: impl Fn(char) -> bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            c if special(c) && c.is_ascii() => escaped.push_str(&format!("\\x{:02X}", c as u32)),
+            c if special(c) => escaped.push_str(&format!("\\u{{{:04X}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Structured fields of a log record, numbers kept as numbers
+#[derive(Default)]
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match value.to_u64() {
+            Some(n) => n.into(),
+            None => value.to_string().into(),
+        };
+        self.0.push((key.to_string(), value));
+        Ok(())
+    }
+}
+
+/// Middleware assigning request IDs and writing the access log
+///
+/// The ID comes from `X-Request-Id` when nginx sends a sane one and is
+/// generated otherwise. It is echoed in the response and attached to every
+/// log line written while the request is handled.
+pub fn access<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<BoxBody>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody + 'static,
+{
+    let started = Instant::now();
+    let id = req.headers().get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| valid_request_id(id))
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+
+    let entry = Entry::from_request(&req);
+    let response = REQUEST_ID.sync_scope(id.clone(), || srv.call(req));
+
+    REQUEST_ID.scope(id.clone(), async move {
+        let mut response = match response.await {
+            Ok(response) => response.map_into_boxed_body(),
+            Err(e) => {
+                // Rare: handler and auth errors arrive as responses already
+                if let Some(entry) = entry {
+                    entry.log(e.as_response_error().status_code().as_u16(), None, started);
+                }
+                return Err(e);
+            }
+        };
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            response.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+        match entry {
+            Some(mut entry) => {
+                // Only known once the validator has accepted the credentials
+                entry.user = response.request().extensions().get::<AuthenticatedUser>()
+                    .map(|user| user.0.clone());
+                let status = response.status().as_u16();
+                Ok(response.map_body(|_, body| {
+                    LoggedBody { body, bytes: 0, pending: Some((entry, id, status, started)) }.boxed()
+                }))
+            }
+            None => Ok(response),
+        }
+    })
+}
+
+/// Response body that writes the access log line once it is sent, or
+/// dropped because the client went away, with the bytes that went out
+///
+/// The size of the body before it is sent is unknown for streamed files
+/// and for anything `Compress` encodes on the fly.
+struct LoggedBody {
+    body: BoxBody,
+    bytes: u64,
+    /// Logged once, the request ID scoped again as the body is polled
+    /// outside of the request's task-local
+    pending: Option<(Entry, String, u16, Instant)>,
+}
+
+impl LoggedBody {
+    fn finish(&mut self) {
+        if let Some((entry, id, status, started)) = self.pending.take() {
+            REQUEST_ID.sync_scope(id, || entry.log(status, Some(self.bytes), started));
+        }
+    }
+}
+
+impl MessageBody for LoggedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = &mut *self;
+        let chunk = Pin::new(&mut this.body).poll_next(cx);
+        match &chunk {
+            Poll::Ready(Some(Ok(bytes))) => this.bytes += bytes.len() as u64,
+            Poll::Ready(None) => this.finish(),
+            _ => {}
+        }
+        chunk
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// Request details captured before the request is handed on
+struct Entry {
+    format: LogFormat,
+    remote: String,
+    /// Authenticated user, never the unchecked name a client sent
+    user: Option<String>,
+    method: String,
+    uri: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Entry {
+    fn from_request(req: &ServiceRequest) -> Option<Self> {
+        if !log::log_enabled!(target: ACCESS_TARGET, Level::Info) {
+            return None;
+        }
+
+        let state = req.app_data::<web::Data<State>>()?.current();
+        let logging = &state.config.logging;
+        let redacted = |name: &str| logging.redact.iter().any(|r| r.eq_ignore_ascii_case(name));
+
+        let headers = logging.headers.iter()
+            .map(|name| {
+                let value = match req.headers().get(name.as_str()).and_then(|v| v.to_str().ok()) {
+                    Some(_) if redacted(name) => REDACTED.to_string(),
+                    Some(value) => value.to_string(),
+                    None => String::new(),
+                };
+                (name.to_ascii_lowercase(), value)
+            })
+            .collect();
+
+        Some(Entry {
+            format: logging.format,
+            remote: ClientAddr::of(req.request()).0.map_or("-".to_string(), |ip| ip.to_string()),
+            user: None,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            version: format!("{:?}", req.version()),
+            headers,
+        })
+    }
+
+    fn log(&self, status: u16, bytes: Option<u64>, started: Instant) {
+        if self.format == LogFormat::Combined {
+            log::info!(target: ACCESS_TARGET, "{}", self.combined(status, bytes));
+            return;
+        }
+
+        let duration_ms = started.elapsed().as_millis() as u64;
+        let mut fields: Vec<(&str, kv::Value)> = vec![
+            ("remote", kv::Value::from(self.remote.as_str())),
+            ("method", kv::Value::from(self.method.as_str())),
+            ("path", kv::Value::from(self.uri.as_str())),
+            ("status", kv::Value::from(status)),
+            ("duration_ms", kv::Value::from(duration_ms)),
+        ];
+        if let Some(bytes) = bytes {
+            fields.push(("bytes", kv::Value::from(bytes)));
+        }
+        if let Some(user) = &self.user {
+            fields.push(("user", kv::Value::from(user.as_str())));
+        }
+        for (name, value) in &self.headers {
+            fields.push((name.as_str(), kv::Value::from(value.as_str())));
+        }
+
+        log::logger().log(
+            &Record::builder()
+                .args(format_args!("{} {} {}", self.method, self.uri, status))
+                .level(Level::Info)
+                .target(ACCESS_TARGET)
+                .key_values(&fields.as_slice())
+                .build(),
+        );
+    }
+
+    /// `remote - user [time] "request" status bytes "referer" "user-agent"`
+    ///
+    /// Quotes, backslashes and control characters are escaped as nginx
+    /// does, as `\xHH`.
+    fn combined(&self, status: u16, bytes: Option<u64>) -> String {
+        let escape = |value: &str| escape(value, |c| c.is_control() || c == '"' || c == '\\');
+        let header = |name: &HeaderName| {
+            self.headers.iter()
+                .find(|(n, _)| n == name.as_str())
+                .map(|(_, value)| value.as_str())
+                .filter(|value| !value.is_empty())
+                .map_or("-".to_string(), escape)
+        };
+
+        format!(
+            "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+            self.remote,
+            self.user.as_deref().map_or("-".to_string(), escape),
+            chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            escape(&self.uri),
+            self.version,
+            status,
+            bytes.map_or("-".to_string(), |b| b.to_string()),
+            header(&header::REFERER),
+            header(&header::USER_AGENT),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::state::{Snapshot, Source};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{middleware, App};
+    use std::sync::{Mutex, OnceLock};
+
+    /// What a logged line carried
+    struct Line {
+        target: String,
+        request_id: Option<String>,
+        message: String,
+        bytes: Option<u64>,
+    }
+
+    /// Every line logged by any test
+    struct Capture(Mutex<Vec<Line>>);
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let mut fields = Fields::default();
+            let _ = record.key_values().visit(&mut fields);
+            let bytes = fields.0.iter().find(|(key, _)| key == "bytes").and_then(|(_, value)| value.as_u64());
+            self.0.lock().unwrap().push(Line {
+                target: record.target().to_string(),
+                request_id: request_id(),
+                message: record.args().to_string(),
+                bytes,
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Install the capturing logger once for the whole test binary
+    fn capture() -> &'static Capture {
+        static CAPTURE: OnceLock<&'static Capture> = OnceLock::new();
+        CAPTURE.get_or_init(|| {
+            let capture: &'static Capture = Box::leak(Box::new(Capture(Mutex::new(Vec::new()))));
+            log::set_logger(capture).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+            capture
+        })
+    }
+
+    #[actix_web::test]
+    async fn access_log_counts_bytes_sent() {
+        let capture = capture();
+        let source = Source { config_path: Some("config.toml".to_string()), overrides: Vec::new() };
+        let state = State::new(Snapshot::load(&source).unwrap(), source);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap(middleware::Compress::default())
+                .wrap_fn(access)
+                .route("/logging-test/file", web::get().to(|| async {
+                    actix_files::NamedFile::open_async("static/css/base.css").await
+                }))
+                .route("/logging-test/block", web::get().to(|| async {
+                    block(|| log::info!(target: "logging-test", "verifying")).await.map(|()| "ok")
+                })),
+        )
+        .await;
+
+        let req = TestRequest::with_uri("/logging-test/file").insert_header(("Accept-Encoding", "gzip"));
+        let resp = call_service(&app, req.to_request()).await;
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        let sent = read_body(resp).await.len() as u64;
+        assert!(sent < std::fs::metadata("static/css/base.css").unwrap().len());
+
+        let req = TestRequest::with_uri("/logging-test/block").insert_header((REQUEST_ID_HEADER, "block-test-id"));
+        let resp = call_service(&app, req.to_request()).await;
+        assert_eq!(read_body(resp).await, "ok");
+
+        let lines = capture.0.lock().unwrap();
+        let access = |uri: &str| lines.iter()
+            .find(|line| line.target == ACCESS_TARGET && line.message.starts_with(&format!("GET {} ", uri)))
+            .unwrap_or_else(|| panic!("no access line for {}", uri));
+        assert_eq!(access("/logging-test/file").bytes, Some(sent));
+        assert_eq!(access("/logging-test/block").bytes, Some(2));
+        assert_eq!(access("/logging-test/block").request_id.as_deref(), Some("block-test-id"));
+
+        // Logged on the blocking pool, still with the request's ID
+        let inside = lines.iter().find(|line| line.target == "logging-test").unwrap();
+        assert_eq!(inside.request_id.as_deref(), Some("block-test-id"));
+    }
+
+    fn entry(user: Option<&str>, uri: &str, user_agent: &str) -> Entry {
+        Entry {
+            format: LogFormat::Combined,
+            remote: "192.0.2.1".to_string(),
+            user: user.map(String::from),
+            method: "GET".to_string(),
+            uri: uri.to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: vec![("user-agent".to_string(), user_agent.to_string())],
+        }
+    }
+
+    #[test]
+    fn combined_escapes_client_data() {
+        let line = entry(Some("eve\n1.2.3.4 - admin"), "/a\"b", "x\"\\y\tz").combined(200, Some(5));
+        assert!(!line.contains(['\n', '\t']), "{}", line);
+        assert!(line.starts_with("192.0.2.1 - eve\\x0A1.2.3.4 - admin ["), "{}", line);
+        assert!(line.contains("\"GET /a\\x22b HTTP/1.1\" 200 5"), "{}", line);
+        assert!(line.ends_with("\"-\" \"x\\x22\\x5Cy\\x09z\""), "{}", line);
+
+        let line = entry(None, "/", "").combined(401, None);
+        assert!(line.starts_with("192.0.2.1 - - ["), "{}", line);
+        assert!(line.ends_with("401 - \"-\" \"-\""), "{}", line);
+    }
+
+    #[test]
+    fn escapes_control_characters() {
+        assert_eq!(escape_control("plain text"), "plain text");
+        assert_eq!(escape_control("a\r\nb\u{1b}[31m\u{85}"), "a\\x0D\\x0Ab\\x1B[31m\\u{0085}");
+    }
+}
@@ -0,0 +1,201 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::{http::header, web, Error, HttpResponse, Result};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use super::state::State;
+
+/// Process-wide metrics; they survive reloads, unlike the state snapshot
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    render_duration: HistogramVec,
+    static_bytes: IntCounter,
+    auth_failures: IntCounterVec,
+    rate_limited: IntCounterVec,
+    screen_detection: IntCounterVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    Metrics::new().expect("metric definitions are valid")
+});
+
+/// The global metrics registry
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("vvoss".to_string()), None)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route, status and language"),
+            &["route", "status", "lang"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time to produce a response"),
+            &["route"],
+        )?;
+        let render_duration = HistogramVec::new(
+            HistogramOpts::new("template_render_duration_seconds", "Time spent rendering templates")
+                .buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25]),
+            &["template"],
+        )?;
+        let static_bytes = IntCounter::new("static_bytes_total", "Bytes of static files served")?;
+        let auth_failures = IntCounterVec::new(
+            Opts::new("auth_failures_total", "Rejected Basic auth attempts"),
+            &["reason"],
+        )?;
+        let rate_limited = IntCounterVec::new(
+            Opts::new("rate_limited_total", "Requests answered with 429"),
+            &["reason"],
+        )?;
+        let screen_detection = IntCounterVec::new(
+            Opts::new("screen_detection_total", "Screen-detection interstitial served or skipped"),
+            &["outcome"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(render_duration.clone()))?;
+        registry.register(Box::new(static_bytes.clone()))?;
+        registry.register(Box::new(auth_failures.clone()))?;
+        registry.register(Box::new(rate_limited.clone()))?;
+        registry.register(Box::new(screen_detection.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            requests,
+            request_duration,
+            render_duration,
+            static_bytes,
+            auth_failures,
+            rate_limited,
+            screen_detection,
+        })
+    }
+
+    /// `route` is the matched pattern, e.g. `/{lang}/portfolio`, to keep
+    /// label cardinality bounded
+    pub fn observe_request(&self, route: &str, status: u16, lang: &str, elapsed: Duration) {
+        self.requests.with_label_values(&[route, &status.to_string(), lang]).inc();
+        self.request_duration.with_label_values(&[route]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_render(&self, template: &str, elapsed: Duration) {
+        self.render_duration.with_label_values(&[template]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn add_static_bytes(&self, bytes: u64) {
+        self.static_bytes.inc_by(bytes);
+    }
+
+    /// `reason` is one of `missing`, `invalid` or `forbidden`
+    pub fn auth_failure(&self, reason: &str) {
+        self.auth_failures.with_label_values(&[reason]).inc();
+    }
+
+    /// `reason` is `rate` (token bucket empty) or `lockout` (auth failures)
+    pub fn rate_limited(&self, reason: &str) {
+        self.rate_limited.with_label_values(&[reason]).inc();
+    }
+
+    /// `outcome` is one of `served`, `skipped` or `disabled` (cookieless)
+    pub fn screen_detection(&self, outcome: &str) {
+        self.screen_detection.with_label_values(&[outcome]).inc();
+    }
+
+    /// All metrics in Prometheus text exposition format
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+/// Middleware counting and timing every request, including those
+/// rejected by authentication
+pub fn track<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let started = Instant::now();
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+
+    // Only configured languages become label values
+    let lang = req.path().split('/').nth(1)
+        .filter(|segment| {
+            req.app_data::<web::Data<State>>()
+                .is_some_and(|state| state.current().config.languages.available.iter().any(|l| l == segment))
+        })
+        .unwrap_or("")
+        .to_string();
+
+    let response = srv.call(req);
+    async move {
+        let response = response.await;
+        let status = match &response {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        metrics().observe_request(&route, status.as_u16(), &lang, started.elapsed());
+        response
+    }
+}
+
+/// Scrape endpoint
+pub async fn scrape() -> Result<HttpResponse> {
+    let body = metrics().render().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, TextEncoder::new().format_type()))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::state::{Snapshot, Source};
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn scrape_reports_tracked_requests() {
+        let source = Source { config_path: Some("config.toml".to_string()), overrides: Vec::new() };
+        let state = State::new(Snapshot::load(&source).unwrap(), source);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap_fn(track)
+                .route("/{lang}/metrics-test", web::get().to(HttpResponse::Ok))
+                .route("/metrics", web::get().to(scrape)),
+        )
+        .await;
+
+        for uri in ["/de/metrics-test", "/de/metrics-test", "/xx/metrics-test", "/metrics-test/missing"] {
+            call_service(&app, TestRequest::with_uri(uri).to_request()).await;
+        }
+
+        let resp = call_service(&app, TestRequest::with_uri("/metrics").to_request()).await;
+        assert!(resp.headers().get(header::CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/plain"));
+        let body = call_and_read_body(&app, TestRequest::with_uri("/metrics").to_request()).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        // Route patterns, not paths, and only configured languages as labels
+        for line in [
+            r#"vvoss_http_requests_total{lang="de",route="/{lang}/metrics-test",status="200"} 2"#,
+            r#"vvoss_http_requests_total{lang="",route="/{lang}/metrics-test",status="200"} 1"#,
+            r#"vvoss_http_request_duration_seconds_count{route="/{lang}/metrics-test"} 3"#,
+        ] {
+            assert!(body.lines().any(|l| l == line), "{} missing from\n{}", line, body);
+        }
+        assert!(body.contains(r#"route="unmatched",status="404""#), "{}", body);
+        assert!(!body.contains("/xx/"), "{}", body);
+    }
+}
@@ -1,5 +1,24 @@
+pub mod accept_language;
+pub mod assets;
 pub mod auth;
+pub mod build_info;
+pub mod cli;
 pub mod client;
+pub mod compress;
 pub mod config;
 pub mod handlers;
+pub mod handoff;
+pub mod health;
+pub mod i18n;
+pub mod knowledge;
+pub mod listeners;
+pub mod logging;
+pub mod metrics;
+pub mod proxy;
+pub mod ratelimit;
+pub mod reload;
+pub mod routes;
+pub mod security;
+pub mod state;
+pub mod static_files;
 pub mod translations;
\ No newline at end of file
@@ -0,0 +1,498 @@
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use std::future::{ready, Future, Ready};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::config::ForwardedHeader;
+use super::state::State;
+
+/// Entry in `trusted_proxies` standing for connections over a Unix socket
+const UNIX: &str = "unix";
+
+/// Signature starting a PROXY protocol v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest PROXY protocol v1 header, including CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Longest PROXY protocol v2 address block accepted (TLVs included)
+const V2_MAX_LEN: usize = 1024;
+
+/// How long a new connection may take to send its PROXY header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Peers whose forwarding header is believed
+#[derive(Clone, Default)]
+pub struct TrustedProxies {
+    unix: bool,
+    nets: Vec<(IpAddr, u8)>,
+    header: ForwardedHeader,
+}
+
+impl TrustedProxies {
+    /// Parse `unix`, plain addresses and CIDR ranges like `10.0.0.0/8`;
+    /// `header` is the one the proxies set
+    pub fn parse(entries: &[String], header: ForwardedHeader) -> Result<Self, String> {
+        let mut proxies = TrustedProxies { header, ..TrustedProxies::default() };
+        for entry in entries {
+            if entry == UNIX {
+                proxies.unix = true;
+                continue;
+            }
+
+            let (addr, prefix) = match entry.split_once('/') {
+                Some((addr, prefix)) => (addr, Some(prefix)),
+                None => (entry.as_str(), None),
+            };
+            let addr: IpAddr = addr.parse()
+                .map_err(|_| format!("invalid trusted proxy `{}`", entry))?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                None => max,
+                Some(prefix) => prefix.parse().ok().filter(|p| *p <= max)
+                    .ok_or_else(|| format!("invalid prefix length in trusted proxy `{}`", entry))?,
+            };
+            // Peers are compared in canonical form, so an IPv4-mapped range
+            // has to become the IPv4 range it stands for
+            let net = match (addr, addr.to_canonical()) {
+                (IpAddr::V6(_), IpAddr::V4(v4)) if prefix >= 96 => (IpAddr::V4(v4), prefix - 96),
+                _ => (addr, prefix),
+            };
+            proxies.nets.push(net);
+        }
+        Ok(proxies)
+    }
+
+    /// `None` is a peer on a Unix socket
+    pub fn trusts(&self, peer: Option<IpAddr>) -> bool {
+        match peer {
+            None => self.unix,
+            Some(ip) => self.nets.iter().any(|(net, prefix)| in_net(ip.to_canonical(), *net, *prefix)),
+        }
+    }
+
+    /// The client address for a request from `peer`
+    ///
+    /// The configured forwarding header is only read when the peer is
+    /// trusted; the other two are ignored, since a proxy passes them through
+    /// from the client. The chain is walked from the right, skipping trusted
+    /// proxies; the first other address is the client.
+    pub fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        if !self.trusts(peer) {
+            return peer;
+        }
+
+        let chain = forwarded_chain(headers, self.header);
+        let mut client = peer;
+        for hop in chain.iter().rev() {
+            match hop {
+                Some(ip) if self.trusts(Some(*ip)) => client = Some(*ip),
+                Some(ip) => return Some(*ip),
+                // `unknown` or obfuscated: nothing further left is reliable
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+fn in_net(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Addresses from the `header` forwarding header, client first
+fn forwarded_chain(headers: &HeaderMap, header: ForwardedHeader) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| -> Vec<String> {
+        headers.get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    };
+
+    match header {
+        // RFC 7239: `for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"`
+        ForwardedHeader::Forwarded => values("forwarded").iter()
+            .map(|element| {
+                element.split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, value)| parse_node(value.trim().trim_matches('"')))
+            })
+            .collect(),
+        ForwardedHeader::XForwardedFor => values("x-forwarded-for").iter().map(|item| parse_node(item)).collect(),
+        ForwardedHeader::XRealIp => values("x-real-ip").iter().take(1).map(|item| parse_node(item)).collect(),
+    }
+}
+
+/// An address with optional port, IPv6 possibly in brackets
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>().ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.split(']').next()?.parse().ok())
+        .map(|ip: IpAddr| ip.to_canonical())
+}
+
+/// Read a PROXY protocol v1 or v2 header off a fresh connection
+///
+/// Returns the original source address, or `None` for `LOCAL`/`UNKNOWN`
+/// connections (health checks by the proxy itself). Exactly the header
+/// bytes are consumed, so HTTP parsing starts right after it.
+pub async fn read_header<T: AsyncRead + Unpin>(io: &mut T) -> std::io::Result<Option<SocketAddr>> {
+    match tokio::time::timeout(HEADER_TIMEOUT, read_header_inner(io)).await {
+        Ok(result) => result,
+        Err(_) => Err(invalid("timed out waiting for PROXY header")),
+    }
+}
+
+async fn read_header_inner<T: AsyncRead + Unpin>(io: &mut T) -> std::io::Result<Option<SocketAddr>> {
+    // Shorter than any complete header of either version
+    let mut start = [0u8; 12];
+    io.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        io.read_exact(&mut fixed).await?;
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        if fixed[0] >> 4 != 2 || len > V2_MAX_LEN {
+            return Err(invalid("unsupported PROXY v2 header"));
+        }
+        let mut block = vec![0u8; len];
+        io.read_exact(&mut block).await?;
+        return parse_v2(fixed[0] & 0x0f, fixed[1], &block);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY header"));
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(io.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    parse_v1(line)
+}
+
+fn parse_v1(line: &str) -> std::io::Result<Option<SocketAddr>> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("bad PROXY v1 source address"))?;
+            let port: u16 = source_port.parse().map_err(|_| invalid("bad PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+fn parse_v2(command: u8, family: u8, block: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+    const LOCAL: u8 = 0x0;
+    const PROXY: u8 = 0x1;
+
+    match command {
+        LOCAL => Ok(None),
+        PROXY => match family >> 4 {
+            // AF_INET: source, destination, source port, destination port
+            0x1 if block.len() >= 12 => {
+                let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+                Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([block[8], block[9]]))))
+            }
+            // AF_INET6
+            0x2 if block.len() >= 36 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&block[..16]);
+                let ip = Ipv6Addr::from(octets);
+                Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([block[32], block[33]]))))
+            }
+            // AF_UNSPEC and AF_UNIX carry no usable client address
+            0x0 | 0x3 => Ok(None),
+            _ => Err(invalid("malformed PROXY v2 address block")),
+        },
+        _ => Err(invalid("unknown PROXY v2 command")),
+    }
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// The resolved client address of a request
+///
+/// Set by [`resolve_client`]; `None` for a Unix socket peer without
+/// trustworthy forwarding information.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub Option<IpAddr>);
+
+impl ClientAddr {
+    /// The resolved address, falling back to the connection peer
+    pub fn of(req: &HttpRequest) -> Self {
+        req.extensions().get::<ClientAddr>().copied()
+            .unwrap_or_else(|| ClientAddr(req.peer_addr().map(|addr| addr.ip())))
+    }
+}
+
+impl FromRequest for ClientAddr {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(ClientAddr::of(req)))
+    }
+}
+
+/// Middleware storing the [`ClientAddr`] of every request for handlers
+/// and the middleware further in
+pub fn resolve_client<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let peer = req.peer_addr().map(|addr| addr.ip().to_canonical());
+    let client = match req.app_data::<web::Data<State>>() {
+        Some(state) => state.current().proxies.resolve(peer, req.headers()),
+        None => peer,
+    };
+    req.extensions_mut().insert(ClientAddr(client));
+    srv.call(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn proxies(entries: &[&str], header: ForwardedHeader) -> TrustedProxies {
+        let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        TrustedProxies::parse(&entries, header).unwrap()
+    }
+
+    fn proxies_for(entries: &[&str]) -> TrustedProxies {
+        proxies(entries, ForwardedHeader::default())
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(HeaderName::from_static(name), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_entries() {
+        let proxies = proxies(&["unix", "10.0.0.0/8", "192.0.2.1", "2001:db8::/32"], ForwardedHeader::Forwarded);
+        assert!(proxies.trusts(None));
+        assert!(proxies.trusts(ip("10.1.2.3")));
+        assert!(proxies.trusts(ip("192.0.2.1")));
+        assert!(!proxies.trusts(ip("192.0.2.2")));
+        assert!(proxies.trusts(ip("2001:db8:1::1")));
+        assert!(!proxies.trusts(ip("2001:db9::1")));
+
+        assert!(!proxies_for(&[]).trusts(None));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        for entry in ["localhost", "10.0.0.0/33", "::1/129", "10.0.0.0/x", "10.0.0.0/"] {
+            assert!(
+                TrustedProxies::parse(&[entry.to_string()], ForwardedHeader::default()).is_err(),
+                "{} was accepted",
+                entry
+            );
+        }
+    }
+
+    #[test]
+    fn in_net_masks_prefix() {
+        let net = "10.0.0.0".parse().unwrap();
+        assert!(in_net("10.255.0.1".parse().unwrap(), net, 8));
+        assert!(!in_net("11.0.0.1".parse().unwrap(), net, 8));
+        assert!(in_net("10.0.0.0".parse().unwrap(), net, 32));
+        assert!(!in_net("10.0.0.1".parse().unwrap(), net, 32));
+
+        // /0 matches everything of the same family
+        assert!(in_net("203.0.113.9".parse().unwrap(), net, 0));
+        assert!(in_net("2001:db8::1".parse().unwrap(), "::".parse().unwrap(), 0));
+        assert!(!in_net("2001:db8::1".parse().unwrap(), net, 0));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_ranges() {
+        // Raw mapped addresses are IPv6; `trusts` canonicalises them first
+        assert!(!in_net("::ffff:10.0.0.1".parse().unwrap(), "10.0.0.0".parse().unwrap(), 8));
+        assert!(proxies_for(&["10.0.0.0/8"]).trusts(ip("::ffff:10.0.0.1")));
+
+        // A mapped range in the config covers the plain IPv4 addresses
+        let mapped = proxies_for(&["::ffff:10.0.0.0/104"]);
+        assert!(mapped.trusts(ip("10.0.0.1")));
+        assert!(mapped.trusts(ip("::ffff:10.0.0.1")));
+        assert!(!mapped.trusts(ip("11.0.0.1")));
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let proxies = proxies_for(&["10.0.0.1"]);
+        let forged = headers(&[("x-forwarded-for", "1.2.3.4")]);
+        assert_eq!(proxies.resolve(ip("192.0.2.7"), &forged), ip("192.0.2.7"));
+        assert_eq!(proxies.resolve(None, &forged), None);
+    }
+
+    #[test]
+    fn resolve_walks_chain_from_the_right() {
+        let proxies = proxies_for(&["unix", "10.0.0.0/8"]);
+        let chain = headers(&[("x-forwarded-for", "1.2.3.4, 198.51.100.1, 10.0.0.2")]);
+        assert_eq!(proxies.resolve(None, &chain), ip("198.51.100.1"));
+
+        // Split over several header lines
+        let split = headers(&[("x-forwarded-for", "1.2.3.4"), ("x-forwarded-for", "10.0.0.2")]);
+        assert_eq!(proxies.resolve(None, &split), ip("1.2.3.4"));
+
+        // Only trusted hops: the leftmost one
+        let internal = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &internal), ip("10.0.0.3"));
+
+        // An unparsable hop stops the walk at the last trusted address
+        let unknown = headers(&[("x-forwarded-for", "1.2.3.4, unknown, 10.0.0.2")]);
+        assert_eq!(proxies.resolve(None, &unknown), ip("10.0.0.2"));
+
+        // No header: the peer itself
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &HeaderMap::new()), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn resolve_only_reads_the_configured_header() {
+        let all = headers(&[
+            ("forwarded", "for=1.2.3.4"),
+            ("x-forwarded-for", "198.51.100.1"),
+            ("x-real-ip", "203.0.113.5"),
+        ]);
+
+        let forwarded = proxies(&["unix"], ForwardedHeader::Forwarded);
+        assert_eq!(forwarded.resolve(None, &all), ip("1.2.3.4"));
+        let forwarded_for = proxies(&["unix"], ForwardedHeader::XForwardedFor);
+        assert_eq!(forwarded_for.resolve(None, &all), ip("198.51.100.1"));
+        let real_ip = proxies(&["unix"], ForwardedHeader::XRealIp);
+        assert_eq!(real_ip.resolve(None, &all), ip("203.0.113.5"));
+
+        // A client-supplied `Forwarded` is ignored when the proxy sets X-Real-IP
+        let forged = headers(&[("forwarded", "for=1.2.3.4")]);
+        assert_eq!(real_ip.resolve(None, &forged), None);
+        assert_eq!(forwarded_for.resolve(None, &forged), None);
+    }
+
+    #[test]
+    fn resolve_parses_forwarded_elements() {
+        let proxies = proxies(&["unix", "10.0.0.0/8"], ForwardedHeader::Forwarded);
+        let value = headers(&[("forwarded", "for=\"[2001:db8::1]:4711\";proto=https, For=10.0.0.2")]);
+        assert_eq!(proxies.resolve(None, &value), ip("2001:db8::1"));
+
+        let obfuscated = headers(&[("forwarded", "for=1.2.3.4, for=_hidden, for=10.0.0.2")]);
+        assert_eq!(proxies.resolve(None, &obfuscated), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn parses_nodes() {
+        let cases = [
+            ("192.0.2.1", ip("192.0.2.1")),
+            ("192.0.2.1:8080", ip("192.0.2.1")),
+            ("2001:db8::1", ip("2001:db8::1")),
+            ("[2001:db8::1]", ip("2001:db8::1")),
+            ("[2001:db8::1]:4711", ip("2001:db8::1")),
+            ("::ffff:192.0.2.1", ip("192.0.2.1")),
+            ("unknown", None),
+            ("_hidden", None),
+            ("", None),
+        ];
+        for (node, expected) in cases {
+            assert_eq!(parse_node(node), expected, "{}", node);
+        }
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 4711 443").unwrap(),
+            Some("[2001:db8::1]:4711".parse().unwrap())
+        );
+        assert_eq!(parse_v1("PROXY UNKNOWN").unwrap(), None);
+        assert_eq!(parse_v1("PROXY UNKNOWN 192.0.2.1 198.51.100.1 1 2").unwrap(), None);
+
+        for line in [
+            "PROXY TCP4 192.0.2.1 198.51.100.1 56324",
+            "PROXY TCP4 example.org 198.51.100.1 56324 443",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 99999 443",
+            "PROXY UDP4 192.0.2.1 198.51.100.1 56324 443",
+            "PROXY  TCP4 192.0.2.1 198.51.100.1 56324 443",
+        ] {
+            assert!(parse_v1(line).is_err(), "{} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn parses_v2_blocks() {
+        let mut v4 = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        v4.extend_from_slice(&56324u16.to_be_bytes());
+        v4.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(parse_v2(0x1, 0x11, &v4).unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut v6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        v6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        v6.extend_from_slice(&4711u16.to_be_bytes());
+        v6.extend_from_slice(&443u16.to_be_bytes());
+        // Trailing TLVs are ignored
+        v6.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        assert_eq!(parse_v2(0x1, 0x21, &v6).unwrap(), Some("[2001:db8::1]:4711".parse().unwrap()));
+
+        // LOCAL, AF_UNSPEC and AF_UNIX carry no client
+        assert_eq!(parse_v2(0x0, 0x11, &v4).unwrap(), None);
+        assert_eq!(parse_v2(0x1, 0x00, &[]).unwrap(), None);
+        assert_eq!(parse_v2(0x1, 0x31, &[0; 216]).unwrap(), None);
+
+        assert!(parse_v2(0x1, 0x11, &v4[..11]).is_err());
+        assert!(parse_v2(0x1, 0x21, &v6[..35]).is_err());
+        assert!(parse_v2(0x1, 0x41, &v4).is_err());
+        assert!(parse_v2(0x2, 0x11, &v4).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_exactly_the_header() {
+        let mut io: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(read_header(&mut io).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(io, b"GET / HTTP/1.1\r\n");
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c, 192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        v2.extend_from_slice(b"GET /");
+        let mut io: &[u8] = &v2;
+        assert_eq!(read_header(&mut io).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(io, b"GET /");
+
+        let mut io: &[u8] = b"GET / HTTP/1.1\r\nHost: x\r\n";
+        assert!(read_header(&mut io).await.is_err());
+    }
+}
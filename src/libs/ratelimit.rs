@@ -0,0 +1,505 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{web, Error, HttpResponse};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::auth::match_path;
+use super::config::RateLimitConfig;
+use super::metrics::metrics;
+use super::proxy::ClientAddr;
+use super::state::State;
+
+/// Clients tracked before idle entries are dropped
+const MAX_TRACKED: usize = 10_000;
+
+/// Entries kept when dropping idle ones is not enough and the least
+/// recently seen have to go as well
+const KEEP_TRACKED: usize = MAX_TRACKED * 3 / 4;
+
+/// Longest lockout exponent, so that doubling cannot overflow
+const MAX_DOUBLINGS: u32 = 16;
+
+/// Source of the current time, replaceable in tests
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Client address; `None` for Unix socket peers without forwarding
+/// headers, which cannot be told apart and are therefore never limited
+type Client = Option<IpAddr>;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Limit the bucket was last filled with, to tell when it is full again
+    rate: f64,
+    burst: f64,
+}
+
+impl Bucket {
+    /// Refilled completely, so it carries no information
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate >= self.burst
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// In-memory token buckets per client and route, and login failure counts
+///
+/// Limits are passed in on every call, so reloaded settings apply to the
+/// buckets already filled.
+pub struct RateLimiter<C = SystemClock> {
+    clock: C,
+    buckets: Mutex<HashMap<(Client, String), Bucket>>,
+    failures: Mutex<HashMap<Client, Failures>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> RateLimiter<C> {
+    pub fn with_clock(clock: C) -> Self {
+        RateLimiter { clock, buckets: Mutex::default(), failures: Mutex::default() }
+    }
+
+    /// Take a token from the site-wide bucket and the one of the most
+    /// specific route limit for `path`; `Err` holds how long to wait
+    ///
+    /// Clients without an address pass, as they do for lockouts: sharing
+    /// one bucket, every visitor behind a proxy that is not trusted would
+    /// be throttled together, health probes included.
+    pub fn check(&self, config: &RateLimitConfig, client: Client, path: &str) -> Result<(), Duration> {
+        if !config.enabled || client.is_none() {
+            return Ok(());
+        }
+
+        let mut limits = Vec::with_capacity(2);
+        if config.rate > 0.0 {
+            limits.push((String::new(), config.rate, config.burst as f64));
+        }
+        if let Some(route) = match_path(&config.routes, path, |route| &route.path) {
+            limits.push((route.path.clone(), route.rate, route.burst as f64));
+        }
+        if limits.is_empty() {
+            return Ok(());
+        }
+
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() > MAX_TRACKED {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+            evict_oldest(&mut buckets, |bucket| bucket.updated);
+        }
+
+        // Only take tokens when every applicable bucket has one
+        let mut wait = Duration::ZERO;
+        for (route, rate, burst) in &limits {
+            let bucket = buckets.entry((client, route.clone()))
+                .or_insert(Bucket { tokens: *burst, updated: now, rate: *rate, burst: *burst });
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(*burst);
+            bucket.updated = now;
+            bucket.rate = *rate;
+            bucket.burst = *burst;
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - bucket.tokens) / rate));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        for (route, _, _) in &limits {
+            if let Some(bucket) = buckets.get_mut(&(client, route.clone())) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remaining lockout of `client`, if any
+    ///
+    /// Clients without an address are never locked out: they would share
+    /// one lockout, and a single bad one could shut everybody out.
+    pub fn locked_out(&self, config: &RateLimitConfig, client: Client) -> Option<Duration> {
+        if !config.enabled || client.is_none() {
+            return None;
+        }
+        let now = self.clock.now();
+        let failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.get(&client)
+            .and_then(|f| f.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// Count a failed login; returns the lockout it triggers, if any
+    pub fn record_failure(&self, config: &RateLimitConfig, client: Client) -> Option<Duration> {
+        let lockout = &config.lockout;
+        if !config.enabled || lockout.after_failures == 0 || client.is_none() {
+            return None;
+        }
+
+        let now = self.clock.now();
+        let reset_after = Duration::from_secs(lockout.reset_after);
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        if failures.len() > MAX_TRACKED {
+            failures.retain(|_, f| {
+                now.duration_since(f.last) < reset_after || f.locked_until.is_some_and(|until| until > now)
+            });
+            evict_oldest(&mut failures, |f| f.last);
+        }
+
+        let entry = failures.entry(client).or_insert(Failures { count: 0, last: now, locked_until: None });
+        if now.duration_since(entry.last) >= reset_after {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+
+        if entry.count < lockout.after_failures {
+            return None;
+        }
+        let doublings = (entry.count - lockout.after_failures).min(MAX_DOUBLINGS);
+        let duration = Duration::from_secs(lockout.initial.saturating_mul(1 << doublings).min(lockout.max));
+        entry.locked_until = Some(now + duration);
+        Some(duration)
+    }
+
+    /// A successful login clears the failure count
+    pub fn record_success(&self, client: Client) {
+        self.failures.lock().unwrap_or_else(|e| e.into_inner()).remove(&client);
+    }
+}
+
+/// Drop the least recently seen entries down to [`KEEP_TRACKED`], so that
+/// the map stays bounded whatever the limits are
+fn evict_oldest<K: Clone + Eq + std::hash::Hash, V>(map: &mut HashMap<K, V>, seen: impl Fn(&V) -> Instant) {
+    if map.len() <= MAX_TRACKED {
+        return;
+    }
+    let mut by_age: Vec<(Instant, K)> = map.iter().map(|(key, value)| (seen(value), key.clone())).collect();
+    by_age.sort_unstable_by_key(|(seen, _)| *seen);
+    for (_, key) in by_age.into_iter().take(map.len() - KEEP_TRACKED) {
+        map.remove(&key);
+    }
+}
+
+/// `429 Too Many Requests` asking the client to wait `wait`
+pub fn too_many_requests(wait: Duration) -> HttpResponse {
+    // Round up, a client retrying early would only be limited again
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, seconds.max(1).to_string()))
+        .body("Too Many Requests")
+}
+
+/// Middleware enforcing the token buckets before authentication runs
+///
+/// Route limits match the percent-decoded path, as auth rules do, so that
+/// `/en/%70ortfolio` counts against a limit on `/{lang}/portfolio`.
+pub fn limit<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let verdict = req.app_data::<web::Data<State>>()
+        .map(|state| {
+            let client = ClientAddr::of(req.request()).0;
+            state.limiter().check(&state.current().config.rate_limit, client, req.match_info().as_str())
+        })
+        .unwrap_or(Ok(()));
+
+    let outcome = match verdict {
+        Ok(()) => Ok(srv.call(req)),
+        Err(wait) => {
+            metrics().rate_limited("rate");
+            Err(req.into_response(too_many_requests(wait)).map_into_right_body())
+        }
+    };
+
+    async move {
+        match outcome {
+            Ok(response) => response.await.map(ServiceResponse::map_into_left_body),
+            Err(limited) => Ok(limited),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::config::{LockoutConfig, RouteLimit};
+    use actix_web::test::TestRequest;
+    use std::sync::Arc;
+
+    /// Clock that only moves when told to
+    #[derive(Clone)]
+    struct FakeClock(Arc<Mutex<Instant>>);
+
+    impl FakeClock {
+        fn new() -> Self {
+            FakeClock(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn config(rate: f64, burst: u32) -> RateLimitConfig {
+        RateLimitConfig { rate, burst, ..RateLimitConfig::default() }
+    }
+
+    fn limiter() -> (RateLimiter<FakeClock>, FakeClock) {
+        let clock = FakeClock::new();
+        (RateLimiter::with_clock(clock.clone()), clock)
+    }
+
+    fn ip(last: u8) -> Client {
+        Some(IpAddr::from([192, 0, 2, last]))
+    }
+
+    #[test]
+    fn allows_burst_then_limits() {
+        let (limiter, _) = limiter();
+        let config = config(1.0, 3);
+
+        for _ in 0..3 {
+            assert!(limiter.check(&config, ip(1), "/en/").is_ok());
+        }
+        let wait = limiter.check(&config, ip(1), "/en/").unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let (limiter, clock) = limiter();
+        let config = config(2.0, 2);
+
+        assert!(limiter.check(&config, ip(1), "/").is_ok());
+        assert!(limiter.check(&config, ip(1), "/").is_ok());
+        assert!(limiter.check(&config, ip(1), "/").is_err());
+
+        clock.advance(Duration::from_millis(500));
+        assert!(limiter.check(&config, ip(1), "/").is_ok());
+        assert!(limiter.check(&config, ip(1), "/").is_err());
+
+        // Never more than a full bucket, however long the pause
+        clock.advance(Duration::from_secs(3600));
+        assert!(limiter.check(&config, ip(1), "/").is_ok());
+        assert!(limiter.check(&config, ip(1), "/").is_ok());
+        assert!(limiter.check(&config, ip(1), "/").is_err());
+    }
+
+    #[test]
+    fn clients_have_separate_buckets() {
+        let (limiter, _) = limiter();
+        let config = config(1.0, 1);
+
+        assert!(limiter.check(&config, ip(1), "/").is_ok());
+        assert!(limiter.check(&config, ip(1), "/").is_err());
+        assert!(limiter.check(&config, ip(2), "/").is_ok());
+        assert!(limiter.check(&config, None, "/").is_ok());
+    }
+
+    #[test]
+    fn route_limit_applies_on_top() {
+        let (limiter, clock) = limiter();
+        let mut config = config(10.0, 10);
+        config.routes.push(RouteLimit { path: "/{lang}/portfolio".to_string(), rate: 0.1, burst: 2 });
+
+        assert!(limiter.check(&config, ip(1), "/en/portfolio").is_ok());
+        assert!(limiter.check(&config, ip(1), "/de/portfolio/x").is_ok());
+        let wait = limiter.check(&config, ip(1), "/en/portfolio").unwrap_err();
+        assert_eq!(wait, Duration::from_secs(10));
+
+        // Other routes only use the site-wide bucket
+        assert!(limiter.check(&config, ip(1), "/en/").is_ok());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(limiter.check(&config, ip(1), "/en/portfolio").is_ok());
+    }
+
+    #[test]
+    fn route_limit_matches_decoded_path() {
+        let (limiter, _) = limiter();
+        let mut config = config(0.0, 0);
+        config.routes.push(RouteLimit { path: "/{lang}/portfolio".to_string(), rate: 0.1, burst: 1 });
+
+        // The path the middleware passes in, decoded like the router does
+        let path = |uri: &str| TestRequest::with_uri(uri).to_http_request().match_info().as_str().to_string();
+        assert!(limiter.check(&config, ip(1), &path("/en/portfolio")).is_ok());
+        assert!(limiter.check(&config, ip(1), &path("/en/%70ortfolio")).is_err());
+        assert!(limiter.check(&config, ip(1), &path("/%65n/portfolio")).is_err());
+    }
+
+    #[test]
+    fn tracked_buckets_stay_bounded() {
+        let (limiter, clock) = limiter();
+        // Only a route limit, whose buckets have not refilled when evicting
+        let mut config = config(0.0, 0);
+        config.routes.push(RouteLimit { path: "/login".to_string(), rate: 0.001, burst: 5 });
+
+        for i in 0..(MAX_TRACKED as u32 + 10) {
+            clock.advance(Duration::from_millis(1));
+            let client = Some(IpAddr::from(i.to_be_bytes()));
+            assert!(limiter.check(&config, client, "/login").is_ok());
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() <= MAX_TRACKED, "{} buckets", buckets.len());
+        // The most recent clients are the ones kept
+        let last = Some(IpAddr::from((MAX_TRACKED as u32 + 9).to_be_bytes()));
+        assert!(buckets.contains_key(&(last, "/login".to_string())));
+    }
+
+    #[test]
+    fn refilled_buckets_are_evicted_by_their_own_limit() {
+        let (limiter, clock) = limiter();
+        let mut config = config(1000.0, 1);
+        config.routes.push(RouteLimit { path: "/login".to_string(), rate: 0.001, burst: 5 });
+
+        assert!(limiter.check(&config, ip(1), "/login").is_ok());
+        clock.advance(Duration::from_secs(1));
+        for i in 0..(MAX_TRACKED as u32) {
+            let client = Some(IpAddr::from(((i + 1) << 8).to_be_bytes()));
+            assert!(limiter.check(&config, client, "/").is_ok());
+        }
+        // The site-wide bucket refilled long ago, the slow route one has not
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key(&(ip(1), String::new())));
+        assert!(buckets.contains_key(&(ip(1), "/login".to_string())));
+    }
+
+    #[test]
+    fn clients_without_address_are_never_limited() {
+        let (limiter, _) = limiter();
+        let mut config = config(20.0, 100);
+        config.routes.push(RouteLimit { path: "/login".to_string(), rate: 1.0, burst: 1 });
+
+        for _ in 0..10_000 {
+            assert!(limiter.check(&config, None, "/en/").is_ok());
+            assert!(limiter.check(&config, None, "/login").is_ok());
+        }
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+
+        // Clients with an address are still limited meanwhile
+        assert!(limiter.check(&config, ip(1), "/login").is_ok());
+        assert!(limiter.check(&config, ip(1), "/login").is_err());
+    }
+
+    #[test]
+    fn clients_without_address_are_never_locked_out() {
+        let (limiter, _) = limiter();
+        let config = lockout_config();
+
+        for _ in 0..10 {
+            assert_eq!(limiter.record_failure(&config, None), None);
+        }
+        assert_eq!(limiter.locked_out(&config, None), None);
+    }
+
+    #[test]
+    fn disabled_limits_nothing() {
+        let (limiter, _) = limiter();
+        let config = RateLimitConfig { enabled: false, ..config(1.0, 1) };
+
+        for _ in 0..10 {
+            assert!(limiter.check(&config, ip(1), "/").is_ok());
+            assert!(limiter.record_failure(&config, ip(1)).is_none());
+        }
+        assert!(limiter.locked_out(&config, ip(1)).is_none());
+    }
+
+    fn lockout_config() -> RateLimitConfig {
+        RateLimitConfig {
+            lockout: LockoutConfig { after_failures: 3, initial: 10, max: 60, reset_after: 300 },
+            ..RateLimitConfig::default()
+        }
+    }
+
+    #[test]
+    fn lockout_grows_progressively() {
+        let (limiter, clock) = limiter();
+        let config = lockout_config();
+
+        assert_eq!(limiter.record_failure(&config, ip(1)), None);
+        assert_eq!(limiter.record_failure(&config, ip(1)), None);
+        assert_eq!(limiter.record_failure(&config, ip(1)), Some(Duration::from_secs(10)));
+        assert_eq!(limiter.locked_out(&config, ip(1)), Some(Duration::from_secs(10)));
+        assert_eq!(limiter.locked_out(&config, ip(2)), None);
+
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(limiter.locked_out(&config, ip(1)), Some(Duration::from_secs(6)));
+
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(limiter.locked_out(&config, ip(1)), None);
+        assert_eq!(limiter.record_failure(&config, ip(1)), Some(Duration::from_secs(20)));
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(limiter.record_failure(&config, ip(1)), Some(Duration::from_secs(40)));
+
+        // Capped at `max`
+        clock.advance(Duration::from_secs(40));
+        assert_eq!(limiter.record_failure(&config, ip(1)), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn success_and_quiet_periods_reset_failures() {
+        let (limiter, clock) = limiter();
+        let config = lockout_config();
+
+        limiter.record_failure(&config, ip(1));
+        limiter.record_failure(&config, ip(1));
+        limiter.record_success(ip(1));
+        assert_eq!(limiter.record_failure(&config, ip(1)), None);
+        assert_eq!(limiter.record_failure(&config, ip(1)), None);
+
+        clock.advance(Duration::from_secs(300));
+        assert_eq!(limiter.record_failure(&config, ip(1)), None);
+        assert_eq!(limiter.record_failure(&config, ip(1)), None);
+        assert_eq!(limiter.record_failure(&config, ip(1)), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        let response = too_many_requests(Duration::from_millis(1500));
+        assert_eq!(response.status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "2");
+    }
+}
@@ -0,0 +1,116 @@
+use actix_web::{rt, web};
+use log::{error, info, warn};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+use super::state::State;
+
+/// How often the dev-mode watcher looks for changed files
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reload on SIGHUP, and on file changes when `[reload] watch` is set
+pub fn spawn(state: Arc<State>) {
+    let hup_state = state.clone();
+    rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Cannot listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading");
+            reload(&hup_state).await;
+        }
+    });
+
+    if state.current().config.reload.watch {
+        rt::spawn(watch(state));
+    }
+}
+
+/// Reload off the worker threads and log the outcome
+pub async fn reload(state: &Arc<State>) {
+    let before = state.current();
+    let target = state.clone();
+
+    match web::block(move || target.reload()).await {
+        Ok(Ok(())) => {
+            let after = state.current();
+            if after.config.server != before.config.server
+                || after.config.metrics != before.config.metrics
+                || after.config.logging.level != before.config.logging.level
+                || after.config.logging.format != before.config.logging.format
+            {
+                warn!("[server], [metrics] and [logging] changes take effect after a restart");
+            }
+            info!("Reloaded configuration, translations, articles and templates");
+        }
+        Ok(Err(e)) => error!("Reload failed, keeping previous state: {}", e),
+        Err(e) => error!("Reload failed, keeping previous state: {}", e),
+    }
+}
+
+/// Poll the watched files and reload when any of them changes
+async fn watch(state: Arc<State>) {
+    info!("Watching config, templates, content and static files for changes");
+    let mut last = fingerprint(&state).await;
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let Some(current) = fingerprint(&state).await else {
+            continue;
+        };
+        if last.is_some_and(|last| last != current) {
+            info!("Change detected, reloading");
+            reload(&state).await;
+        }
+        last = Some(current);
+    }
+}
+
+/// Walk the watched files off the async threads, `None` if the walk failed
+async fn fingerprint(state: &Arc<State>) -> Option<(usize, Option<SystemTime>)> {
+    let state = state.clone();
+    match web::block(move || walk(&state)).await {
+        Ok(fingerprint) => Some(fingerprint),
+        Err(e) => {
+            error!("Cannot check watched files: {}", e);
+            None
+        }
+    }
+}
+
+/// Count and newest modification time of all watched files
+fn walk(state: &State) -> (usize, Option<SystemTime>) {
+    let snapshot = state.current();
+    let config_path = state.source().config_path.clone().unwrap_or_else(|| "config.toml".to_string());
+
+    let mut result = (0, None);
+    for root in [
+        config_path.as_str(),
+        snapshot.config.templates.path.as_str(),
+        snapshot.config.static_files.path.as_str(),
+        snapshot.config.content.path.as_str(),
+    ] {
+        scan(Path::new(root), &mut result);
+    }
+    result
+}
+
+fn scan(path: &Path, result: &mut (usize, Option<SystemTime>)) {
+    if path.is_dir() {
+        if let Ok(entries) = std::fs::read_dir(path) {
+            for entry in entries.flatten() {
+                scan(&entry.path(), result);
+            }
+        }
+    } else if let Ok(modified) = std::fs::metadata(path).and_then(|m| m.modified()) {
+        result.0 += 1;
+        result.1 = result.1.max(Some(modified));
+    }
+}
@@ -0,0 +1,38 @@
+use actix_web::web;
+
+use super::handlers::{index, portfolio, knowledge, knowledge_article, impressum, redirect_to_language, version};
+use super::health;
+use super::static_files;
+
+/// Register all application routes
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        // Redirect root to default language
+        .route("/", web::get().to(redirect_to_language))
+        .route("/portfolio", web::get().to(redirect_to_language))
+        .route("/knowledge", web::get().to(redirect_to_language))
+        .route("/knowledge/{slug}", web::get().to(redirect_to_language))
+        .route("/impressum", web::get().to(redirect_to_language))
+
+        // Language-specific routes
+        .route("/{lang}/", web::get().to(index))
+        .route("/{lang}/portfolio", web::get().to(portfolio))
+        .route("/{lang}/knowledge", web::get().to(knowledge))
+        .route("/{lang}/knowledge/{slug}", web::get().to(knowledge_article))
+        .route("/{lang}/impressum", web::get().to(impressum))
+
+        // Build provenance
+        .route("/version", web::get().to(version))
+
+        // Liveness, readiness and runtime status
+        .route("/healthz", web::get().to(health::healthz))
+        .route("/readyz", web::get().to(health::readyz))
+        .route("/status", web::get().to(health::status))
+
+        // Static files (no language prefix)
+        .service(
+            web::resource("/static/{filename:.*}")
+                .route(web::get().to(static_files::serve))
+                .route(web::head().to(static_files::serve)),
+        );
+}
@@ -0,0 +1,176 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use std::future::Future;
+
+use super::auth::match_path;
+use super::config::SecurityConfig;
+use super::state::State;
+
+/// Placeholder in the configured policy replaced with the request's nonce
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+/// Nonce for inline `<script>` and `<style>` elements of one response
+#[derive(Clone)]
+struct CspNonce(String);
+
+/// The CSP nonce of `req`, empty outside the [`headers`] middleware
+pub fn nonce(req: &HttpRequest) -> String {
+    req.extensions().get::<CspNonce>().map(|n| n.0.clone()).unwrap_or_default()
+}
+
+/// Header values for `path`, route overrides applied
+///
+/// `path` is the percent-decoded path the router and the auth rules match,
+/// so that `/en/%70ortfolio` gets the headers of `/en/portfolio`.
+fn resolve(config: &SecurityConfig, path: &str) -> Vec<(HeaderName, String)> {
+    let route = match_path(&config.routes, path, |route| &route.path);
+    let pick = |site: &String, route: Option<&Option<String>>| {
+        route.and_then(Option::clone).unwrap_or_else(|| site.clone())
+    };
+
+    vec![
+        (header::CONTENT_SECURITY_POLICY, pick(&config.content_security_policy, route.map(|r| &r.content_security_policy))),
+        (header::STRICT_TRANSPORT_SECURITY, pick(&config.strict_transport_security, route.map(|r| &r.strict_transport_security))),
+        (header::X_CONTENT_TYPE_OPTIONS, pick(&config.content_type_options, route.map(|r| &r.content_type_options))),
+        (header::REFERRER_POLICY, pick(&config.referrer_policy, route.map(|r| &r.referrer_policy))),
+        (HeaderName::from_static("permissions-policy"), pick(&config.permissions_policy, route.map(|r| &r.permissions_policy))),
+        (header::X_FRAME_OPTIONS, pick(&config.frame_options, route.map(|r| &r.frame_options))),
+    ]
+}
+
+/// Middleware adding the configured security headers to every response
+///
+/// A fresh nonce is generated per request and substituted into the CSP, so
+/// templates can mark their inline scripts with `nonce="{{ csp_nonce }}"`.
+/// Headers a handler has set itself are left alone.
+pub fn headers<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let headers = req.app_data::<web::Data<State>>()
+        .map(|state| state.current())
+        .filter(|snapshot| snapshot.config.security.enabled)
+        .map(|snapshot| {
+            let nonce = uuid::Uuid::new_v4().simple().to_string();
+            let headers = resolve(&snapshot.config.security, req.match_info().as_str()).into_iter()
+                .filter(|(_, value)| !value.is_empty())
+                .filter_map(|(name, value)| {
+                    HeaderValue::from_str(&value.replace(NONCE_PLACEHOLDER, &nonce)).ok().map(|v| (name, v))
+                })
+                .collect::<Vec<_>>();
+            req.extensions_mut().insert(CspNonce(nonce));
+            headers
+        })
+        .unwrap_or_default();
+
+    let response = srv.call(req);
+    async move {
+        let mut response = response.await?;
+        let existing = response.headers_mut();
+        for (name, value) in headers {
+            if !existing.contains_key(&name) {
+                existing.insert(name, value);
+            }
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::config::SecurityRoute;
+    use crate::libs::routes;
+    use crate::libs::state::{Snapshot, Source};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    fn state(enabled: bool) -> State {
+        let source = Source {
+            config_path: Some("config.toml".to_string()),
+            overrides: vec![
+                ("auth.enabled".to_string(), "false".to_string()),
+                ("security.enabled".to_string(), enabled.to_string()),
+            ],
+        };
+        State::new(Snapshot::load(&source).unwrap(), source)
+    }
+
+    /// Every `nonce="..."` attribute in `html`
+    fn nonce_attributes(html: &str) -> Vec<&str> {
+        html.split("nonce=\"").skip(1).filter_map(|rest| rest.split('"').next()).collect()
+    }
+
+    #[actix_web::test]
+    async fn adds_headers_with_the_template_nonce() {
+        for enabled in [true, false] {
+            let app = init_service(
+                App::new()
+                    .app_data(web::Data::new(state(enabled)))
+                    .wrap_fn(headers)
+                    .route("/framed", web::get().to(|| async {
+                        HttpResponse::Ok().insert_header((header::X_FRAME_OPTIONS, "SAMEORIGIN")).finish()
+                    }))
+                    .configure(routes::configure),
+            )
+            .await;
+
+            let page = || TestRequest::with_uri("/en/").insert_header(("Sec-CH-Viewport-Width", "1280"));
+            let resp = call_service(&app, page().to_request()).await;
+            assert_eq!(resp.status(), 200);
+            let csp = resp.headers().get(header::CONTENT_SECURITY_POLICY).map(|v| v.to_str().unwrap().to_string());
+            let hsts = resp.headers().get(header::STRICT_TRANSPORT_SECURITY).cloned();
+            let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+            let nonces = nonce_attributes(&body);
+
+            if !enabled {
+                // Nothing added, and templates get an empty nonce
+                assert!(csp.is_none() && hsts.is_none());
+                assert!(nonces.iter().all(|n| n.is_empty()), "{:?}", nonces);
+                let resp = call_service(&app, TestRequest::with_uri("/framed").to_request()).await;
+                assert_eq!(resp.headers().get(header::X_FRAME_OPTIONS).unwrap(), "SAMEORIGIN");
+                assert_eq!(resp.headers().get(header::X_CONTENT_TYPE_OPTIONS), None);
+                continue;
+            }
+
+            let csp = csp.unwrap();
+            assert!(!csp.contains(NONCE_PLACEHOLDER), "{}", csp);
+            assert!(hsts.is_some());
+            assert!(!nonces.is_empty());
+            assert!(nonces.iter().all(|nonce| *nonce == nonces[0]), "{:?}", nonces);
+            for nonce in &nonces {
+                assert_eq!(nonce.len(), 32);
+                assert!(csp.contains(&format!("'nonce-{}'", nonce)), "{} not in {}", nonce, csp);
+            }
+
+            // A fresh nonce for every response
+            let again = call_service(&app, page().to_request()).await;
+            let again = again.headers().get(header::CONTENT_SECURITY_POLICY).unwrap().to_str().unwrap();
+            assert_ne!(again, csp);
+
+            // Headers the handler set win over the configured ones
+            let resp = call_service(&app, TestRequest::with_uri("/framed").to_request()).await;
+            assert_eq!(resp.headers().get(header::X_FRAME_OPTIONS).unwrap(), "SAMEORIGIN");
+            assert_eq!(resp.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        }
+    }
+
+    #[test]
+    fn route_overrides_match_decoded_paths() {
+        let route: SecurityRoute = toml::from_str("path = \"/{lang}/portfolio\"\nframe_options = \"DENY\"").unwrap();
+        let config = SecurityConfig { frame_options: "SAMEORIGIN".to_string(), routes: vec![route], ..SecurityConfig::default() };
+
+        let frame_options = |uri: &str| {
+            let req = TestRequest::with_uri(uri).to_http_request();
+            resolve(&config, req.match_info().as_str()).into_iter()
+                .find(|(name, _)| name == header::X_FRAME_OPTIONS)
+                .map(|(_, value)| value)
+                .unwrap()
+        };
+        assert_eq!(frame_options("/en/portfolio"), "DENY");
+        assert_eq!(frame_options("/en/%70ortfolio"), "DENY");
+        assert_eq!(frame_options("/%65n/portfolio/x"), "DENY");
+        assert_eq!(frame_options("/en/"), "SAMEORIGIN");
+    }
+}
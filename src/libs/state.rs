@@ -0,0 +1,208 @@
+use arc_swap::ArcSwap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tera::Tera;
+
+use super::assets::AssetManifest;
+use super::auth::Credentials;
+use super::config::Config;
+use super::knowledge::Knowledge;
+use super::proxy::TrustedProxies;
+use super::ratelimit::RateLimiter;
+use super::translations::{Translate, Translations};
+
+/// Everything loaded from disk that handlers read, swapped as a whole
+pub struct Snapshot {
+    pub config: Config,
+    pub credentials: Credentials,
+    pub translations: Translations,
+    pub knowledge: Knowledge,
+    pub assets: AssetManifest,
+    pub proxies: TrustedProxies,
+    pub tera: Tera,
+}
+
+/// Where the configuration comes from, kept so reloads see the same layers
+#[derive(Clone, Default)]
+pub struct Source {
+    pub config_path: Option<String>,
+    pub overrides: Vec<(String, String)>,
+}
+
+impl Snapshot {
+    /// Load config, credentials, translations, articles, assets and templates
+    pub fn load(source: &Source) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = Config::load(source.config_path.as_deref(), &source.overrides)?;
+        Self::from_config(config)
+    }
+
+    /// Load everything else for an already parsed config
+    pub fn from_config(mut config: Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let credentials = Credentials::from_config(&config.auth)
+            .map_err(|e| format!("auth credentials: {}", e))?;
+        let translations = Translations::from_dir(&config.templates.translations_dir())
+            .map_err(|e| format!("translations: {}", e))?;
+        config.languages.resolve_locales(translations.strings.keys().map(String::as_str));
+        for locale in config.languages.locales() {
+            if !translations.has_locale(&locale.locale) {
+                return Err(format!(
+                    "translations: language `{}` has no strings for locale `{}`", locale.code, locale.locale
+                ).into());
+            }
+        }
+        let knowledge = Knowledge::from_dir(&config.content.knowledge_dir(), &config.languages.available)
+            .map_err(|e| format!("knowledge articles: {}", e))?;
+        let assets = AssetManifest::from_dir(&config.static_files.path)
+            .map_err(|e| format!("asset manifest: {}", e))?;
+
+        let proxies = TrustedProxies::parse(&config.server.trusted_proxies, config.server.forwarded_header)
+            .map_err(|e| format!("trusted proxies: {}", e))?;
+
+        let mut tera = Tera::new(&config.templates.glob())
+            .map_err(|e| format!("templates: {}", e))?;
+        tera.register_function("asset", assets.clone());
+        tera.register_function("t", Translate::new(translations.clone(), config.languages.clone(), config.languages.mark_missing));
+
+        Ok(Snapshot { config, credentials, translations, knowledge, assets, proxies, tera })
+    }
+}
+
+/// Shared handle to the current snapshot
+pub struct State {
+    current: ArcSwap<Snapshot>,
+    source: Source,
+    started: Instant,
+    /// Worker threads the server was started with
+    workers: usize,
+    ready: AtomicBool,
+    /// Outlives reloads so that buckets and lockouts are not reset
+    limiter: RateLimiter,
+}
+
+impl State {
+    pub fn new(snapshot: Snapshot, source: Source) -> Self {
+        let workers = snapshot.config.server.worker_count();
+        State {
+            current: ArcSwap::from_pointee(snapshot),
+            source,
+            started: Instant::now(),
+            workers,
+            ready: AtomicBool::new(false),
+            limiter: RateLimiter::new(),
+        }
+    }
+
+    /// The snapshot in effect right now; a request keeps using it even if a
+    /// reload happens meanwhile
+    pub fn current(&self) -> Arc<Snapshot> {
+        self.current.load_full()
+    }
+
+    /// Re-read everything and swap it in; on error the old state stays
+    pub fn reload(&self) -> Result<(), String> {
+        let snapshot = Snapshot::load(&self.source).map_err(|e| e.to_string())?;
+        self.current.store(Arc::new(snapshot));
+        Ok(())
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.source
+    }
+
+    /// Called once the workers are up and the server accepts requests
+    pub fn mark_ready(&self) {
+        self.ready.store(true, Ordering::Release);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn failed_reloads_keep_the_previous_snapshot() {
+        let dir = std::env::temp_dir().join(format!("vvoss-reload-{}", std::process::id()));
+        let templates = dir.join("templates");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&templates).unwrap();
+        std::os::unix::fs::symlink(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("templates/translations"),
+            templates.join("translations"),
+        ).unwrap();
+        std::fs::write(templates.join("broken.tera"), "{% if %}").unwrap();
+
+        let original = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/config.toml")).unwrap();
+        let config_path = dir.join("config.toml");
+        let write = |contents: &str| std::fs::write(&config_path, contents).unwrap();
+        write(&original);
+
+        let source = Source { config_path: Some(config_path.to_string_lossy().into_owned()), overrides: Vec::new() };
+        let state = State::new(Snapshot::load(&source).unwrap(), source);
+        let before = state.current();
+
+        let broken_templates = original.replace(
+            "[templates]\npath = \"templates\"",
+            &format!("[templates]\npath = \"{}\"", templates.display()),
+        );
+        let missing_strings = original.replace("available = [\"de\", \"en\"]", "available = [\"de\", \"en\", \"fr\"]");
+        assert_ne!(broken_templates, original);
+        assert_ne!(missing_strings, original);
+
+        for (contents, error) in [
+            ("[server\nworkers = 2", "expected"),
+            (broken_templates.as_str(), "templates: "),
+            (missing_strings.as_str(), "language `fr` has no strings"),
+        ] {
+            write(contents);
+            let err = state.reload().unwrap_err();
+            assert!(err.contains(error), "{}", err);
+            assert!(Arc::ptr_eq(&before, &state.current()), "{}", err);
+        }
+
+        // A good config is picked up again
+        write(&original.replace("workers = 4", "workers = 2"));
+        state.reload().unwrap();
+        assert!(!Arc::ptr_eq(&before, &state.current()));
+        assert_eq!(state.current().config.server.workers, Some(2));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn languages_without_locale_tables_find_their_strings() {
+        let dir = std::env::temp_dir().join(format!("vvoss-locales-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let original = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/config.toml")).unwrap();
+        let (available_only, _) = original.split_once("[languages.locales.").unwrap();
+        let config_path = dir.join("config.toml");
+        std::fs::write(&config_path, available_only).unwrap();
+
+        let source = Source { config_path: Some(config_path.to_string_lossy().into_owned()), overrides: Vec::new() };
+        let snapshot = Snapshot::load(&source).unwrap();
+        let languages = &snapshot.config.languages;
+        assert_eq!(languages.available, ["de", "en"]);
+        assert_eq!(languages.locale("de").locale, "de-DE");
+        assert_eq!(languages.locale("en").locale, "en-EN");
+        assert_eq!(languages.locale("de").html_lang, "de");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+use actix_files::NamedFile;
+use actix_web::http::header::{
+    AcceptEncoding, ContentEncoding, Encoding, Header, HeaderValue, CACHE_CONTROL, RANGE, VARY,
+};
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::Method;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use std::path::{Component, Path, PathBuf};
+
+use super::compress::is_compressible;
+use super::metrics::metrics;
+use super::state::State;
+
+/// One year, the longest lifetime caches are expected to honour
+const IMMUTABLE_MAX_AGE: u32 = 31_536_000;
+
+/// Resolve a requested path against the static root
+///
+/// `requested` is already percent-decoded by the router and is used as is,
+/// so `%2525` names a file called `%25`. Anything with `..`, hidden
+/// segments, backslashes or NUL bytes is rejected, and the final canonical
+/// path must still live below the canonical root, which also catches
+/// symlinks pointing outside of it.
+pub fn resolve_path(root: &Path, requested: &str) -> Option<PathBuf> {
+    if requested.contains('\0') || requested.contains('\\') {
+        return None;
+    }
+
+    let mut relative = PathBuf::new();
+    for segment in requested.split('/') {
+        if segment.is_empty() {
+            continue;
+        }
+        if segment.starts_with('.') {
+            return None;
+        }
+        relative.push(segment);
+    }
+
+    // Defence in depth: only plain file names may remain
+    if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+
+    let root = root.canonicalize().ok()?;
+    let full = root.join(&relative).canonicalize().ok()?;
+
+    if full.starts_with(&root) && full.is_file() {
+        Some(full)
+    } else {
+        None
+    }
+}
+
+/// Pick a precompressed `.br`/`.gz` sibling the client accepts
+///
+/// Range requests always get the identity representation so that byte
+/// offsets refer to the file itself. Siblings older than `source` are
+/// left over from before an edit and are ignored, so every client gets
+/// the current content.
+fn negotiate_precompressed(
+    req: &HttpRequest,
+    root: &Path,
+    requested: &str,
+    source: &Path,
+) -> Option<(PathBuf, ContentEncoding)> {
+    if req.headers().contains_key(RANGE) {
+        return None;
+    }
+
+    let modified = std::fs::metadata(source).and_then(|m| m.modified()).ok()?;
+
+    let available: Vec<(Encoding, ContentEncoding, PathBuf)> = [
+        (Encoding::brotli(), ContentEncoding::Brotli, "br"),
+        (Encoding::gzip(), ContentEncoding::Gzip, "gz"),
+    ]
+    .into_iter()
+    .filter_map(|(encoding, content_encoding, ext)| {
+        resolve_path(root, &format!("{}.{}", requested, ext))
+            .filter(|path| {
+                std::fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|t| t >= modified)
+            })
+            .map(|path| (encoding, content_encoding, path))
+    })
+    .collect();
+
+    if available.is_empty() {
+        return None;
+    }
+
+    let accept = AcceptEncoding::parse(req).ok()?;
+    let mut supported = vec![Encoding::identity()];
+    supported.extend(available.iter().map(|(encoding, _, _)| encoding.clone()));
+    let chosen = accept.negotiate(supported.iter())?;
+
+    available.into_iter()
+        .find(|(encoding, _, _)| *encoding == chosen)
+        .map(|(_, content_encoding, path)| (path, content_encoding))
+}
+
+/// Serve static files (CSS, JS, images, fonts)
+///
+/// `NamedFile` takes care of `ETag`/`Last-Modified` with 304 answers,
+/// byte ranges and HEAD. Content-hashed names from the asset manifest
+/// never change, so they are cached as immutable. Precompressed siblings
+/// are preferred; otherwise small or already compressed files are marked
+/// `identity` so the `Compress` middleware leaves them alone, and
+/// [`tidy_headers`](super::compress::tidy_headers) removes that marker.
+pub async fn serve(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<State>,
+) -> Result<HttpResponse> {
+    let state = state.current();
+    let (config, assets) = (&state.config, &state.assets);
+
+    let (requested, immutable) = match assets.logical_for(&path) {
+        Some(logical) => (logical.to_string(), true),
+        None => (path.into_inner(), false),
+    };
+
+    let root = Path::new(&config.static_files.path);
+    let file_path = match resolve_path(root, &requested) {
+        Some(file_path) => file_path,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let compressible = is_compressible(&file_path);
+
+    let file = match negotiate_precompressed(&req, root, &requested, &file_path) {
+        Some((sibling, encoding)) => {
+            let content_type = file_path.extension()
+                .and_then(|s| s.to_str())
+                .map(actix_files::file_extension_to_mime)
+                .unwrap_or(actix_web::mime::APPLICATION_OCTET_STREAM);
+            NamedFile::open_async(&sibling).await
+                .map(|file| file.set_content_type(content_type).set_content_encoding(encoding))
+        }
+        None => NamedFile::open_async(&file_path).await.map(|file| {
+            let small = file.metadata().len() < config.static_files.compress_min_size;
+            if !compressible || small {
+                file.set_content_encoding(ContentEncoding::Identity)
+            } else {
+                file
+            }
+        }),
+    };
+
+    let file = match file {
+        Ok(file) => file,
+        Err(_) => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let mut response = file
+        .use_etag(true)
+        .use_last_modified(true)
+        .prefer_utf8(true)
+        .disable_content_disposition()
+        .into_response(&req);
+
+    // Bytes on the wire before on-the-fly compression; HEAD sends none
+    if req.method() != Method::HEAD {
+        if let BodySize::Sized(bytes) = response.body().size() {
+            metrics().add_static_bytes(bytes);
+        }
+    }
+
+    if compressible {
+        response.headers_mut().insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+
+    let cache_control = if immutable {
+        format!("public, max-age={}, immutable", IMMUTABLE_MAX_AGE)
+    } else {
+        format!("public, max-age={}", config.static_files.cache_max_age)
+    };
+    if let Ok(value) = HeaderValue::from_str(&cache_control) {
+        response.headers_mut().insert(CACHE_CONTROL, value);
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, init_service, TestRequest};
+    use actix_web::App;
+
+    fn root() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("static")
+    }
+
+    #[test]
+    fn serves_files_inside_root() {
+        let resolved = resolve_path(&root(), "css/base.css").unwrap();
+        assert!(resolved.ends_with("static/css/base.css"));
+        assert!(resolve_path(&root(), "/css//base.css").is_some());
+    }
+
+    #[test]
+    fn rejects_parent_segments() {
+        for attack in [
+            "../Cargo.toml",
+            "css/../../Cargo.toml",
+            "css/../base.css",
+            "..",
+            "../../../../etc/passwd",
+        ] {
+            assert!(resolve_path(&root(), attack).is_none(), "{}", attack);
+        }
+    }
+
+    /// The file name the static route extracts from `uri`
+    async fn extracted(uri: &str) -> String {
+        let app = init_service(App::new().route(
+            "/static/{filename:.*}",
+            web::get().to(|path: web::Path<String>| async move { path.into_inner() }),
+        )).await;
+        let body = call_and_read_body(&app, TestRequest::with_uri(uri).to_request()).await;
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn request_paths_are_decoded_once() {
+        for (uri, expected) in [
+            ("/static/css/base.css", "css/base.css"),
+            ("/static/css%2Fbase.css", "css/base.css"),
+            ("/static/%2525", "%25"),
+            ("/static/%252e%252e/Cargo.toml", "%2e%2e/Cargo.toml"),
+        ] {
+            assert_eq!(extracted(uri).await, expected, "{}", uri);
+        }
+        assert!(resolve_path(&root(), &extracted("/static/css%2Fbase.css").await).is_some());
+    }
+
+    #[actix_web::test]
+    async fn rejects_encoded_separators_and_dots() {
+        for attack in [
+            "/static/%2e%2e/Cargo.toml",
+            "/static/%2E%2E%2FCargo.toml",
+            "/static/..%2fCargo.toml",
+            "/static/css%2f..%2f..%2fCargo.toml",
+            "/static/%252e%252e/Cargo.toml",
+            "/static/..%5cCargo.toml",
+            "/static/css%5c..%5c..%5cCargo.toml",
+            "/static/css/base.css%00.png",
+        ] {
+            assert!(resolve_path(&root(), &extracted(attack).await).is_none(), "{}", attack);
+        }
+    }
+
+    #[test]
+    fn rejects_hidden_files_and_directories() {
+        assert!(resolve_path(&root(), ".gitignore").is_none());
+        assert!(resolve_path(&root(), "css/.hidden").is_none());
+        assert!(resolve_path(&root(), "css").is_none());
+    }
+
+    #[test]
+    fn ignores_siblings_older_than_the_source() {
+        let root = std::env::temp_dir().join(format!("vvoss-siblings-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let file = root.join("app.css");
+        for path in [file.clone(), root.join("app.css.br"), root.join("app.css.gz")] {
+            std::fs::write(&path, "body {}").unwrap();
+        }
+        let modified = std::fs::metadata(&file).unwrap().modified().unwrap();
+        let backdate = |ext: &str| {
+            std::fs::File::options().write(true).open(root.join(format!("app.css.{}", ext))).unwrap()
+                .set_modified(modified - std::time::Duration::from_secs(60)).unwrap();
+        };
+        let negotiate = || {
+            let req = TestRequest::default().insert_header(("Accept-Encoding", "br, gzip")).to_http_request();
+            negotiate_precompressed(&req, &root, "app.css", &file).map(|(_, encoding)| encoding)
+        };
+
+        assert_eq!(negotiate(), Some(ContentEncoding::Brotli));
+        backdate("br");
+        assert_eq!(negotiate(), Some(ContentEncoding::Gzip));
+        backdate("gz");
+        assert_eq!(negotiate(), None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
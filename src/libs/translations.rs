@@ -1,46 +1,746 @@
+use log::warn;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::path::Path;
+
+use super::config::LanguagesConfig;
+
+/// Column separator of `strings.csv`
+const SEPARATOR: char = ';';
+
+/// Columns `strings.csv` must have, in any order
+const COLUMNS: [&str; 3] = ["key", "text", "locale"];
 
 #[derive(Clone)]
 pub struct Translations {
     pub strings: HashMap<String, HashMap<String, String>>,
+    /// Keys defined more than once for a locale
+    pub duplicates: Vec<Duplicate>,
+}
+
+/// A key defined twice; the later definition is the one used
+#[derive(Clone)]
+pub struct Duplicate {
+    pub locale: String,
+    pub key: String,
+    /// `file:line` of the overridden and the effective definition
+    pub first: String,
+    pub second: String,
+}
+
+/// Strings collected from several files, remembering where each came from
+#[derive(Default)]
+struct Loader {
+    strings: HashMap<String, HashMap<String, String>>,
+    origins: HashMap<(String, String), String>,
+    duplicates: Vec<Duplicate>,
+}
+
+impl Loader {
+    /// Add a string; a key defined twice for a locale keeps the later text
+    fn insert(&mut self, locale: &str, key: &str, text: String, origin: String) {
+        if let Some(previous) = self.origins.insert((locale.to_string(), key.to_string()), origin.clone()) {
+            warn!("Translation `{}` for {} in {} overrides {}", key, locale, origin, previous);
+            self.duplicates.push(Duplicate {
+                locale: locale.to_string(),
+                key: key.to_string(),
+                first: previous,
+                second: origin,
+            });
+        }
+        self.strings.entry(locale.to_string()).or_default().insert(key.to_string(), text);
+    }
+
+    fn finish(self) -> Translations {
+        Translations { strings: self.strings, duplicates: self.duplicates }
+    }
 }
 
 impl Translations {
-    /// Load translations from CSV file
+    /// Load `strings.csv` and any `<locale>.toml` or `<locale>.ftl` files
+    /// from the translations directory
+    ///
+    /// Files are read in name order, so for a key defined twice the
+    /// alphabetically later file wins, with a warning.
+    pub fn from_dir(dir: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut loader = Loader::default();
+
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .map_err(|e| format!("{}: {}", dir, e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let extension = path.extension().and_then(|s| s.to_str()).unwrap_or("");
+            if !["csv", "toml", "ftl"].contains(&extension) {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            let name = path.display().to_string();
+            match extension {
+                "csv" => load_csv(&mut loader, &name, &source),
+                "toml" => load_toml(&mut loader, &name, locale_of(&path)?, &source),
+                _ => load_ftl(&mut loader, &name, locale_of(&path)?, &source),
+            }
+            .map_err(|e| format!("{}:{}", name, e))?;
+        }
+
+        Ok(loader.finish())
+    }
+
+    /// Load translations from a single CSV file
     pub fn from_csv(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let csv_content = std::fs::read_to_string(path)?;
-        let mut strings: HashMap<String, HashMap<String, String>> = HashMap::new();
-        
-        for (i, line) in csv_content.lines().enumerate() {
-            if i == 0 { continue; } // Skip header
-            
-            let parts: Vec<&str> = line.split(';').collect();
-            if parts.len() == 3 {
-                let key = parts[0];
-                let text = parts[1];
-                let locale = parts[2];
-                
-                strings.entry(locale.to_string())
-                    .or_insert_with(HashMap::new)
-                    .insert(key.to_string(), text.to_string());
-            }
-        }
-        
-        Ok(Translations { strings })
-    }
-    
-    /// Get translation for a key in a specific locale
-    pub fn get(&self, locale: &str, key: &str) -> Option<&str> {
-        self.strings.get(locale)
-            .and_then(|locale_strings| locale_strings.get(key))
-            .map(|s| s.as_str())
-    }
-    
-    /// Get all translations for a locale with fallback
-    pub fn get_locale_with_fallback(&self, locale: &str, fallback: &str) -> HashMap<String, String> {
-        self.strings.get(locale)
-            .or_else(|| self.strings.get(fallback))
-            .cloned()
-            .unwrap_or_default()
-    }
-}
\ No newline at end of file
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let mut loader = Loader::default();
+        load_csv(&mut loader, path, &source).map_err(|e| format!("{}:{}", path, e))?;
+        Ok(loader.finish())
+    }
+
+    /// Strings of the first locale, keys missing there taken from the
+    /// following ones in order
+    pub fn merged<'a>(&self, locales: impl IntoIterator<Item = &'a str>) -> HashMap<String, String> {
+        let mut merged = HashMap::new();
+        for locale in locales {
+            for (key, text) in self.strings.get(locale).into_iter().flatten() {
+                merged.entry(key.clone()).or_insert_with(|| text.clone());
+            }
+        }
+        merged
+    }
+
+    /// Whether `locale` has at least one string
+    pub fn has_locale(&self, locale: &str) -> bool {
+        self.strings.get(locale).is_some_and(|strings| !strings.is_empty())
+    }
+}
+
+thread_local! {
+    /// URL code of the language the template being rendered is in
+    static LANGUAGE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Run `render` with `t()` translating into `language`
+pub fn with_language<R>(language: &str, render: impl FnOnce() -> R) -> R {
+    let previous = LANGUAGE.replace(Some(language.to_string()));
+    let result = render();
+    LANGUAGE.set(previous);
+    result
+}
+
+/// Tera function `t(key="knowledge.count", count=3, ...)`
+///
+/// Looks `key` up along the language's fallback chain. With `count`, the
+/// CLDR plural category picks `key.one` or `key.other` first. Other
+/// arguments replace `{name}` placeholders. `lang` overrides the language
+/// of the page. A missing key renders as the key itself, or as a visible
+/// marker with `mark_missing` (development).
+pub struct Translate {
+    translations: Translations,
+    languages: LanguagesConfig,
+    mark_missing: bool,
+}
+
+impl Translate {
+    pub fn new(translations: Translations, languages: LanguagesConfig, mark_missing: bool) -> Self {
+        Translate { translations, languages, mark_missing }
+    }
+
+    fn lookup(&self, language: &str, key: &str, count: Option<&tera::Value>) -> Option<&String> {
+        self.languages.chain(language).iter().find_map(|locale| {
+            let strings = self.translations.strings.get(&locale.locale)?;
+            let category = count.map(|count| plural_category(&locale.code, count));
+            category.and_then(|category| strings.get(&format!("{}.{}", key, category)))
+                .or_else(|| count.and_then(|_| strings.get(&format!("{}.other", key))))
+                .or_else(|| strings.get(key))
+        })
+    }
+}
+
+impl tera::Function for Translate {
+    fn call(&self, args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+        let key = args.get("key")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tera::Error::msg("t() requires a `key` string argument"))?;
+        let language = match args.get("lang") {
+            Some(lang) => lang.as_str()
+                .ok_or_else(|| tera::Error::msg("t(): `lang` must be a string"))?
+                .to_string(),
+            None => LANGUAGE.with_borrow(|language| language.clone())
+                .unwrap_or_else(|| self.languages.default_code().to_string()),
+        };
+
+        let text = match self.lookup(&language, key, args.get("count")) {
+            Some(text) => interpolate(text, args),
+            None if self.mark_missing => format!("[missing: {}]", key),
+            None => key.to_string(),
+        };
+        Ok(tera::Value::String(text))
+    }
+}
+
+/// CLDR plural category of `count` for a language
+///
+/// German and English share one rule: `one` for the integer 1, `other`
+/// for everything else, including `1.0`. Languages without a rule here
+/// always get `other`.
+pub fn plural_category(language: &str, count: &tera::Value) -> &'static str {
+    let primary = language.split('-').next().unwrap_or(language);
+    match primary.to_ascii_lowercase().as_str() {
+        "de" | "en" if count.as_i64() == Some(1) => "one",
+        _ => "other",
+    }
+}
+
+/// Replace `{name}` with the argument `name`; unknown placeholders stay
+fn interpolate(text: &str, args: &HashMap<String, tera::Value>) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find('{') {
+        result.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let value = after.find('}')
+            .map(|close| (&after[..close], close))
+            .and_then(|(name, close)| args.get(name).map(|value| (value, close)));
+        match value {
+            Some((value, close)) => {
+                match value {
+                    tera::Value::String(s) => result.push_str(s),
+                    other => result.push_str(&other.to_string()),
+                }
+                rest = &after[close + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// The locale a per-locale file is for, from its name: `de-DE.ftl`
+fn locale_of(path: &Path) -> Result<&str, String> {
+    path.file_stem().and_then(|s| s.to_str())
+        .filter(|stem| !stem.is_empty())
+        .ok_or_else(|| format!("{}: cannot tell the locale from the file name", path.display()))
+}
+
+/// `key;text;locale` records as in RFC 4180, with `;` as separator
+///
+/// Fields containing `;`, quotes or line breaks are enclosed in double
+/// quotes, a quote inside them is doubled. Errors are prefixed with the
+/// line number.
+fn load_csv(loader: &mut Loader, name: &str, source: &str) -> Result<(), String> {
+    let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+    let mut records = parse_csv(source)?.into_iter();
+
+    let (header_line, header) = records.next().ok_or("1: missing header line")?;
+    let column = |name: &str| {
+        header.iter().position(|h| h.trim().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("{}: missing column `{}` in header, expected {}", header_line, name, COLUMNS.join(";")))
+    };
+    let (key, text, locale) = (column(COLUMNS[0])?, column(COLUMNS[1])?, column(COLUMNS[2])?);
+
+    for (line, fields) in records {
+        if fields.len() != header.len() {
+            return Err(format!("{}: expected {} fields, found {}", line, header.len(), fields.len()));
+        }
+        if fields[key].is_empty() || fields[locale].is_empty() {
+            return Err(format!("{}: empty key or locale", line));
+        }
+        loader.insert(&fields[locale], &fields[key], fields[text].clone(), format!("{}:{}", name, line));
+    }
+    Ok(())
+}
+
+/// Split CSV into records, each with the line it starts on
+///
+/// Records end at `\r\n`, `\n` or a lone `\r`. Blank lines are skipped.
+fn parse_csv(source: &str) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let mut records = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+
+        loop {
+            match chars.next() {
+                Some('"') if field.is_empty() && !quoted => {
+                    quoted = true;
+                    // Up to the closing quote; `""` is a literal quote
+                    let opened = line;
+                    loop {
+                        match chars.next() {
+                            Some('"') if chars.peek() == Some(&'"') => {
+                                chars.next();
+                                field.push('"');
+                            }
+                            Some('"') => break,
+                            Some('\r') if chars.peek() == Some(&'\n') => {}
+                            Some(c) => {
+                                if c == '\n' || c == '\r' {
+                                    line += 1;
+                                }
+                                field.push(c);
+                            }
+                            None => return Err(format!("{}: unterminated quoted field", opened)),
+                        }
+                    }
+                    match chars.peek() {
+                        Some(&SEPARATOR) | Some('\n') | Some('\r') | None => {}
+                        Some(_) => return Err(format!("{}: text after closing quote", line)),
+                    }
+                }
+                Some('"') => return Err(format!("{}: quote inside unquoted field, enclose the field in quotes", line)),
+                Some(SEPARATOR) => {
+                    fields.push(std::mem::take(&mut field));
+                    quoted = false;
+                }
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some('\r') | Some('\n') | None => {
+                    line += 1;
+                    break;
+                }
+                Some(c) => field.push(c),
+            }
+        }
+
+        fields.push(field);
+        let blank = fields.len() == 1 && fields[0].is_empty() && !quoted;
+        if !blank {
+            records.push((start, fields));
+        }
+    }
+    Ok(records)
+}
+
+/// A `<locale>.toml` file: string values, nested tables giving dotted keys
+fn load_toml(loader: &mut Loader, name: &str, locale: &str, source: &str) -> Result<(), String> {
+    // Parsed with spans kept, so that every string knows its line
+    let document = toml_edit::ImDocument::parse(source)
+        .map_err(|e| format!("{}: {}", e.span().map_or(1, |span| line_of(source, span.start)), e.message().trim_end().replace('\n', ", ")))?;
+
+    fn walk(loader: &mut Loader, name: &str, locale: &str, source: &str, prefix: &str, table: &dyn toml_edit::TableLike) -> Result<(), String> {
+        for (key, item) in table.iter() {
+            let line = table.get_key_value(key)
+                .and_then(|(key, _)| key.span())
+                .map_or(1, |span| line_of(source, span.start));
+            let key = if prefix.is_empty() { key.to_string() } else { format!("{}.{}", prefix, key) };
+            match item {
+                toml_edit::Item::Value(toml_edit::Value::String(text)) => {
+                    loader.insert(locale, &key, text.value().clone(), format!("{}:{}", name, line))
+                }
+                toml_edit::Item::Value(toml_edit::Value::InlineTable(table)) => walk(loader, name, locale, source, &key, table)?,
+                toml_edit::Item::Table(table) => walk(loader, name, locale, source, &key, table)?,
+                _ => return Err(format!("{}: `{}` must be a string", line, key)),
+            }
+        }
+        Ok(())
+    }
+    walk(loader, name, locale, source, "", document.as_table())
+}
+
+/// 1-based line number of byte `offset`
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+/// A `<locale>.ftl` file in a subset of Fluent
+///
+/// Supported are messages (`id = value`), attributes (`.name = value`,
+/// giving the key `id.name`), indented continuation lines, comments,
+/// variables (`{ $count }`, stored as `{count}`) and string literals
+/// (`{ "{" }`). Message ids may contain dots. Terms and select expressions
+/// are rejected.
+fn load_ftl(loader: &mut Loader, name: &str, locale: &str, source: &str) -> Result<(), String> {
+    /// Key, value lines and line number of an entry being read
+    type Entry = (String, Vec<String>, usize);
+
+    fn flush(loader: &mut Loader, name: &str, locale: &str, entry: Option<Entry>) -> Result<(), String> {
+        let Some((key, mut lines, line)) = entry else { return Ok(()) };
+        while lines.last().is_some_and(|l| l.is_empty()) {
+            lines.pop();
+        }
+        // A message with attributes only has no value of its own
+        if !lines.is_empty() {
+            let text = ftl_placeables(&lines.join("\n"), line)?;
+            loader.insert(locale, &key, text, format!("{}:{}", name, line));
+        }
+        Ok(())
+    }
+
+    let value_lines = |value: &str| match value.trim() {
+        "" => Vec::new(),
+        value => vec![value.to_string()],
+    };
+
+    let mut current: Option<Entry> = None;
+    let mut message: Option<String> = None;
+
+    for (index, raw) in source.lines().enumerate() {
+        let line = index + 1;
+        let trimmed = raw.trim();
+
+        if trimmed.is_empty() {
+            // Kept if more text of the value follows
+            if let Some((_, lines, _)) = current.as_mut().filter(|(_, lines, _)| !lines.is_empty()) {
+                lines.push(String::new());
+            }
+            continue;
+        }
+
+        if raw.starts_with([' ', '\t']) {
+            if let Some(attribute) = trimmed.strip_prefix('.') {
+                let id = message.as_ref().ok_or_else(|| format!("{}: attribute outside a message", line))?;
+                let (attribute, value) = attribute.split_once('=')
+                    .ok_or_else(|| format!("{}: expected `.attribute = value`", line))?;
+                let attribute = attribute.trim();
+                if !valid_ftl_id(attribute) {
+                    return Err(format!("{}: invalid attribute name `{}`", line, attribute));
+                }
+                flush(loader, name, locale, current.take())?;
+                current = Some((format!("{}.{}", id, attribute), value_lines(value), line));
+            } else {
+                let (_, lines, _) = current.as_mut()
+                    .ok_or_else(|| format!("{}: indented text outside a message", line))?;
+                lines.push(trimmed.to_string());
+            }
+            continue;
+        }
+
+        flush(loader, name, locale, current.take())?;
+        message = None;
+        if trimmed.starts_with('#') {
+            continue;
+        }
+        if trimmed.starts_with('-') {
+            return Err(format!("{}: terms are not supported", line));
+        }
+        let (id, value) = trimmed.split_once('=')
+            .ok_or_else(|| format!("{}: expected `id = value`", line))?;
+        let id = id.trim();
+        if !valid_ftl_id(id) {
+            return Err(format!("{}: invalid message id `{}`", line, id));
+        }
+        message = Some(id.to_string());
+        current = Some((id.to_string(), value_lines(value), line));
+    }
+    flush(loader, name, locale, current)
+}
+
+/// `[a-zA-Z][a-zA-Z0-9_.-]*`
+fn valid_ftl_id(id: &str) -> bool {
+    id.starts_with(|c: char| c.is_ascii_alphabetic())
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+}
+
+/// Turn `{ $name }` into `{name}` and `{ "text" }` into `text`
+fn ftl_placeables(text: &str, line: usize) -> Result<String, String> {
+    let unsupported = || format!("{}: unsupported placeable, only `{{ $variable }}` and `{{ \"literal\" }}`", line);
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '}' {
+            return Err(format!("{}: unmatched `}}`", line));
+        }
+        if c != '{' {
+            result.push(c);
+            continue;
+        }
+
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.next() {
+            Some('$') => {
+                let mut variable = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || "_-".contains(*c)) {
+                    variable.push(c);
+                }
+                if !valid_ftl_id(&variable) {
+                    return Err(unsupported());
+                }
+                result.push('{');
+                result.push_str(&variable);
+                result.push('}');
+            }
+            Some('"') => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => result.push(chars.next().ok_or_else(unsupported)?),
+                    Some(c) => result.push(c),
+                    None => return Err(unsupported()),
+                }
+            },
+            _ => return Err(unsupported()),
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next() != Some('}') {
+            return Err(unsupported());
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `(locale, key, text, origin)`
+    type Loaded<S> = (S, S, S, S);
+
+    /// Every string loaded, sorted
+    fn loaded(loader: &Loader) -> Vec<Loaded<String>> {
+        let mut strings: Vec<_> = loader.strings.iter()
+            .flat_map(|(locale, strings)| strings.iter().map(move |(key, text)| {
+                let origin = loader.origins[&(locale.clone(), key.clone())].clone();
+                (locale.clone(), key.clone(), text.clone(), origin)
+            }))
+            .collect();
+        strings.sort();
+        strings
+    }
+
+    fn strings(expected: &[Loaded<&str>]) -> Vec<Loaded<String>> {
+        expected.iter()
+            .map(|(locale, key, text, origin)| (locale.to_string(), key.to_string(), text.to_string(), origin.to_string()))
+            .collect()
+    }
+
+    fn csv(source: &str) -> Result<Loader, String> {
+        let mut loader = Loader::default();
+        load_csv(&mut loader, "s.csv", source).map(|_| loader)
+    }
+
+    #[test]
+    fn loads_csv() {
+        let cases: &[(&str, &[Loaded<&str>])] = &[
+            ("key;text;locale\nhello;\"say \"\"hi\"\"\";en\n", &[("en", "hello", "say \"hi\"", "s.csv:2")]),
+            ("key;text;locale\na;\"x;y\";en\nb;z;en", &[("en", "a", "x;y", "s.csv:2"), ("en", "b", "z", "s.csv:3")]),
+            // A record spanning lines keeps its first line, the next one counts on
+            ("key;text;locale\na;\"x\ny\";en\nb;z;en\n", &[("en", "a", "x\ny", "s.csv:2"), ("en", "b", "z", "s.csv:4")]),
+            ("key;text;locale\r\na;\"x\r\ny\";en\r\nb;z;en\r\n", &[("en", "a", "x\ny", "s.csv:2"), ("en", "b", "z", "s.csv:4")]),
+            // A lone carriage return ends a record, after a quote as well
+            ("key;locale;text\ra;en;\"x\"\rb;en;y\r", &[("en", "a", "x", "s.csv:2"), ("en", "b", "y", "s.csv:3")]),
+            // Columns in any order, blank lines skipped, a BOM ignored
+            ("\u{feff}\n\nlocale ; KEY;text\n\nde;a;x\n", &[("de", "a", "x", "s.csv:5")]),
+        ];
+
+        for (source, expected) in cases {
+            let loader = csv(source).unwrap_or_else(|e| panic!("{:?}: {}", source, e));
+            assert_eq!(loaded(&loader), strings(expected), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn reports_csv_errors_with_line_numbers() {
+        let cases = [
+            ("", "1: missing header line"),
+            ("key;text\na;b\n", "1: missing column `locale` in header, expected key;text;locale"),
+            ("\n\nkey;locale\n", "3: missing column `text` in header, expected key;text;locale"),
+            ("key;text;locale\na;x;en\n\"b\nc\";en\n", "3: expected 3 fields, found 2"),
+            ("key;text;locale\na;x;en\rb;y\r", "3: expected 3 fields, found 2"),
+            ("key;text;locale\na;x;en\n;y;en\n", "3: empty key or locale"),
+            ("key;text;locale\na;\"x\";en\nb;\"y\n", "3: unterminated quoted field"),
+            ("key;text;locale\na;\"x\"y;en\n", "2: text after closing quote"),
+            ("key;text;locale\n\na;x\"y;en\n", "3: quote inside unquoted field, enclose the field in quotes"),
+            ("key;text;locale\r\na;\"x\r\ny\"z;en\r\n", "3: text after closing quote"),
+        ];
+
+        for (source, expected) in cases {
+            assert_eq!(csv(source).err().as_deref(), Some(expected), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn duplicate_csv_keys_keep_the_later_text() {
+        let loader = csv("key;text;locale\na;x;en\na;x;de\n\n\"a\";y;en\n").unwrap();
+        assert_eq!(loader.strings["en"]["a"], "y");
+        assert_eq!(loader.strings["de"]["a"], "x");
+
+        let duplicates = loader.finish().duplicates;
+        assert_eq!(duplicates.len(), 1);
+        let duplicate = &duplicates[0];
+        assert_eq!((duplicate.locale.as_str(), duplicate.key.as_str()), ("en", "a"));
+        assert_eq!((duplicate.first.as_str(), duplicate.second.as_str()), ("s.csv:2", "s.csv:5"));
+    }
+
+    fn toml(source: &str) -> Result<Loader, String> {
+        let mut loader = Loader::default();
+        load_toml(&mut loader, "en.toml", "en", source).map(|_| loader)
+    }
+
+    #[test]
+    fn loads_toml() {
+        let loader = toml("title = \"Home\"\n\n[nav]\nabout = \"\"\"\nAbout\nus\"\"\"\n[nav.sub]\nx = 'y'\n").unwrap();
+        assert_eq!(loaded(&loader), strings(&[
+            ("en", "nav.about", "About\nus", "en.toml:4"),
+            ("en", "nav.sub.x", "y", "en.toml:8"),
+            ("en", "title", "Home", "en.toml:1"),
+        ]));
+
+        let cases = [
+            ("title = \"Home\"\ncount = 3\n", "2: `count` must be a string"),
+            ("[nav]\nabout = \"x\"\n\n[nav.sub]\nlist = [\"a\"]\n", "5: `nav.sub.list` must be a string"),
+            ("c = \"x\"\nb = 1979-05-27\n", "2: `b` must be a string"),
+            ("a.b = \"x\"\na.c = 1\n", "2: `a.c` must be a string"),
+            ("a.b = 1979-05-27\n", "1: `a.b` must be a string"),
+            ("a = { b = \"x\", c = true }\n", "1: `a.c` must be a string"),
+            ("a = \"x\"\nb = \n", "2: invalid string, expected `\"`, `'`"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(toml(source).err().as_deref(), Some(expected), "{:?}", source);
+        }
+    }
+
+    fn ftl(source: &str) -> Result<Loader, String> {
+        let mut loader = Loader::default();
+        load_ftl(&mut loader, "en.ftl", "en", source).map(|_| loader)
+    }
+
+    #[test]
+    fn loads_ftl() {
+        let source = "# Comment\nhello = Hello { $name }!\n\nnav =\n    .about = About\nbody =\n    First\n\n    second { \"{\" }\n";
+        assert_eq!(loaded(&ftl(source).unwrap()), strings(&[
+            ("en", "body", "First\n\nsecond {", "en.ftl:6"),
+            ("en", "hello", "Hello {name}!", "en.ftl:2"),
+            ("en", "nav.about", "About", "en.ftl:5"),
+        ]));
+
+        let cases = [
+            ("a = x\n-term = y\n", "2: terms are not supported"),
+            ("a = x\n\n  .1b = y\n", "3: invalid attribute name `1b`"),
+            ("  text\n", "1: indented text outside a message"),
+            ("a = x\nb\n", "2: expected `id = value`"),
+            ("a = x\nb = { $n ->\n", "2: unsupported placeable, only `{ $variable }` and `{ \"literal\" }`"),
+            ("a = x }\n", "1: unmatched `}`"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(ftl(source).err().as_deref(), Some(expected), "{:?}", source);
+        }
+    }
+
+    fn translate(mark_missing: bool) -> Translate {
+        let languages: LanguagesConfig = toml::from_str(r#"
+            available = ["de", "en"]
+            [locales.de]
+            locale = "de-DE"
+            fallback = ["en"]
+            [locales.en]
+            locale = "en-EN"
+        "#).unwrap();
+        let mut loader = Loader::default();
+        load_csv(&mut loader, "s.csv", "key;text;locale\n\
+            greeting;Hallo {name};de-DE\n\
+            greeting;Hello {name};en-EN\n\
+            imprint;Imprint;en-EN\n\
+            articles.one;Ein Artikel;de-DE\n\
+            articles.other;{count} Artikel;de-DE\n\
+            articles.one;One article;en-EN\n\
+            articles.other;{count} articles;en-EN\n\
+            visits;{count} visits;en-EN\n").unwrap();
+        Translate::new(loader.finish(), languages, mark_missing)
+    }
+
+    fn call(t: &Translate, args: &[(&str, tera::Value)]) -> String {
+        let args = args.iter().map(|(name, value)| (name.to_string(), value.clone())).collect();
+        tera::Function::call(t, &args).unwrap().as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn looks_keys_up_along_the_fallback_chain() {
+        let t = translate(false);
+        let key = |key: &str| ("key", tera::Value::from(key));
+        let lang = |lang: &str| ("lang", tera::Value::from(lang));
+
+        assert_eq!(call(&t, &[key("greeting"), lang("de"), ("name", "Ada".into())]), "Hallo Ada");
+        assert_eq!(call(&t, &[key("greeting"), lang("en"), ("name", "Ada".into())]), "Hello Ada");
+        // German falls back to English, English has no fallback
+        assert_eq!(call(&t, &[key("imprint"), lang("de")]), "Imprint");
+        // The page language, and the default language outside a page
+        assert_eq!(with_language("en", || call(&t, &[key("greeting")])), "Hello {name}");
+        assert_eq!(call(&t, &[key("greeting")]), "Hallo {name}");
+    }
+
+    #[test]
+    fn picks_plural_forms_by_count() {
+        let t = translate(false);
+        let cases = [
+            ("de", 1.into(), "Ein Artikel"),
+            ("de", 0.into(), "0 Artikel"),
+            ("de", 2.into(), "2 Artikel"),
+            ("en", 1.into(), "One article"),
+            ("en", 21.into(), "21 articles"),
+            ("en", 1.5.into(), "1.5 articles"),
+        ];
+        for (lang, count, expected) in cases {
+            let args = [("key", "articles".into()), ("lang", lang.into()), ("count", count)];
+            assert_eq!(call(&t, &args), expected, "{} {:?}", lang, args[2].1);
+        }
+
+        // Without plural forms the key itself, through the fallback too
+        let args = [("key", "visits".into()), ("lang", "de".into()), ("count", 1.into())];
+        assert_eq!(call(&t, &args), "1 visits");
+    }
+
+    #[test]
+    fn missing_keys_are_marked_only_when_configured() {
+        let args = [("key", tera::Value::from("nav.missing")), ("lang", "de".into())];
+        assert_eq!(call(&translate(false), &args), "nav.missing");
+        assert_eq!(call(&translate(true), &args), "[missing: nav.missing]");
+
+        let t = translate(false);
+        let missing_key: HashMap<String, tera::Value> = HashMap::new();
+        assert!(tera::Function::call(&t, &missing_key).is_err());
+    }
+
+    #[test]
+    fn plural_categories() {
+        let cases: &[(&str, tera::Value, &str)] = &[
+            ("de", 1.into(), "one"),
+            ("de-AT", 1.into(), "one"),
+            ("de", 0.into(), "other"),
+            ("de", 2.into(), "other"),
+            ("de", (-1).into(), "other"),
+            ("EN", 1.into(), "one"),
+            ("en-GB", 1.into(), "one"),
+            ("en", 1.0.into(), "other"),
+            ("en", 11.into(), "other"),
+            ("en", "1".into(), "other"),
+            // No rule here, so always `other`
+            ("ja", 1.into(), "other"),
+        ];
+        for (language, count, expected) in cases {
+            assert_eq!(plural_category(language, count), *expected, "{} {:?}", language, count);
+        }
+    }
+
+    #[test]
+    fn interpolates_placeholders() {
+        let args: HashMap<String, tera::Value> = [
+            ("name".to_string(), tera::Value::from("Ada")),
+            ("count".to_string(), tera::Value::from(3)),
+        ].into_iter().collect();
+
+        let cases = [
+            ("{name} has {count} items", "Ada has 3 items"),
+            ("{name}{name}", "AdaAda"),
+            ("{unknown} stays", "{unknown} stays"),
+            ("{ name }", "{ name }"),
+            ("{ {name}", "{ Ada"),
+            ("open { brace", "open { brace"),
+            ("}{", "}{"),
+            ("no placeholders", "no placeholders"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(interpolate(text, &args), expected, "{:?}", text);
+        }
+    }
+}
@@ -1,77 +1,195 @@
 use actix_web::{middleware, web, App, HttpServer};
 use actix_web_httpauth::middleware::HttpAuthentication;
-use std::os::unix::net::UnixListener;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
-use tera::Tera;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
 use log::info;
 
-mod libs;
+use vvoss_web::libs::auth::validator;
+use vvoss_web::libs::cli::{Cli, USAGE};
+use vvoss_web::libs::compress::{self, precompress};
+use vvoss_web::libs::config::Config;
+use vvoss_web::libs::handoff;
+use vvoss_web::libs::i18n;
+use vvoss_web::libs::listeners::{self, Listener};
+use vvoss_web::libs::logging;
+use vvoss_web::libs::metrics;
+use vvoss_web::libs::proxy;
+use vvoss_web::libs::ratelimit;
+use vvoss_web::libs::reload;
+use vvoss_web::libs::security;
+use vvoss_web::libs::routes;
+use vvoss_web::libs::state::{Snapshot, Source, State};
 
-use libs::auth::validator;
-use libs::config::Config;
-use libs::translations::Translations;
-use libs::handlers::{index, portfolio, knowledge, impressum, static_files, redirect_to_language};
+fn main() -> std::io::Result<()> {
+    // Hand-off variables are cleared before the runtime starts its threads
+    let handoff = handoff::Handoff::from_env();
+    actix_web::rt::System::new().block_on(run(handoff))
+}
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+async fn run(handoff: handoff::Handoff) -> std::io::Result<()> {
+    let cli = match Cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+    if cli.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
 
-    // Load configuration
-    let config = Config::from_file("config.toml")
-        .expect("Failed to load config.toml");
-    
-    let socket_path = config.server.socket_path.clone();
+    // Load configuration: defaults, file, VVOSS_* environment, --set
+    let config = match Config::load(cli.config_path.as_deref(), &cli.overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // Load translations
-    let translations = Translations::from_csv("templates/translations/strings.csv")
-        .expect("Failed to load translations");
+    logging::init(&config.logging);
 
-    info!("Loading templates...");
-    let tera = Tera::new("templates/**/*.tera")
-        .expect("Failed to load templates");
-    
-    // Remove socket if it exists
-    if Path::new(&socket_path).exists() {
-        std::fs::remove_file(&socket_path)?;
+    match cli.command.first().map(|s| s.as_str()) {
+        None => {}
+        // `vvoss-web precompress` writes .br/.gz siblings and exits
+        Some("precompress") => {
+            let written = precompress(&config.static_files.path)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            info!("Precompressed {} files in {}", written, config.static_files.path);
+            return Ok(());
+        }
+        // `vvoss-web i18n check` lints templates against the translations
+        Some("i18n") if cli.command.get(1).map(|s| s.as_str()) == Some("check") => {
+            match i18n::check(&config) {
+                Ok(report) => {
+                    println!("{}", report);
+                    std::process::exit(report.exit_code());
+                }
+                Err(e) => {
+                    eprintln!("i18n check failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(other) => {
+            eprintln!("unknown command `{}`\n\n{}", other, USAGE);
+            std::process::exit(2);
+        }
     }
+    
+    let server_config = config.server.clone();
+    let metrics_config = config.metrics.clone();
 
-    info!("Starting server on Unix socket: {}", socket_path);
+    // Load credentials, translations, articles, assets and templates
+    info!("Loading templates, translations and content...");
+    let snapshot = Snapshot::from_config(config)
+        .expect("Failed to load application state");
+    let source = Source { config_path: cli.config_path, overrides: cli.overrides };
+    let state = Arc::new(State::new(snapshot, source));
 
-    // Create Unix listener
-    let listener = UnixListener::bind(&socket_path)?;
+    // Reload on SIGHUP (and file changes in watch mode)
+    reload::spawn(state.clone());
     
-    // Set socket permissions for nginx access
-    std::fs::set_permissions(
-        &socket_path,
-        std::fs::Permissions::from_mode(0o666)
-    )?;
-
-    HttpServer::new(move || {
-        let auth = HttpAuthentication::basic(validator);
+    // Take over sockets from systemd or a previous process, if handed any
+    let inherited = handoff.fds.clone();
+    let (site_listeners, metrics_listener) = if inherited.is_empty() {
+        let metrics_listener = metrics_config.listener()
+            .map(|addr| listeners::bind_addr(&server_config, addr))
+            .transpose()?;
+        (listeners::bind_all(&server_config)?, metrics_listener)
+    } else {
+        info!("Using {} inherited socket(s)", inherited.len());
+        let mut site = Vec::new();
+        let mut metrics_listener = None;
+        for (fd, name) in inherited {
+            let listener = unsafe { Listener::from_raw_fd(fd) };
+            if name == listeners::METRICS {
+                metrics_listener = Some(listener);
+            } else {
+                site.push(listener);
+            }
+        }
+        (site, metrics_listener)
+    };
+
+    // SIGUSR2 starts a new binary on the same sockets for zero-downtime deploys
+    let mut named: Vec<_> = site_listeners.iter()
+        .map(|l| (l.as_raw_fd(), listeners::SITE.to_string()))
+        .collect();
+    named.extend(metrics_listener.iter().map(|l| (l.as_raw_fd(), listeners::METRICS.to_string())));
+    handoff::spawn_upgrade_on_sigusr2(named);
+
+    let app_state = state.clone();
+    let site_metrics = metrics_config.clone();
+    let app = move || {
+        let auth = HttpAuthentication::with_fn(validator);
         
-        App::new()
-            .app_data(web::Data::new(config.clone()))
-            .app_data(web::Data::new(tera.clone()))
-            .app_data(web::Data::new(translations.clone()))
-            .wrap(middleware::Logger::default())
+        let mut app = App::new()
+            .app_data(web::Data::from(app_state.clone()))
+            .wrap(middleware::Compress::default())
+            .wrap_fn(compress::tidy_headers)
             .wrap(auth)
-            // Redirect root to default language
-            .route("/", web::get().to(redirect_to_language))
-            .route("/portfolio", web::get().to(redirect_to_language))
-            .route("/knowledge", web::get().to(redirect_to_language))
-            .route("/impressum", web::get().to(redirect_to_language))
-            
-            // Language-specific routes
-            .route("/{lang}/", web::get().to(index))
-            .route("/{lang}/portfolio", web::get().to(portfolio))
-            .route("/{lang}/knowledge", web::get().to(knowledge))
-            .route("/{lang}/impressum", web::get().to(impressum))
-            
-            // Static files (no language prefix)
-            .route("/static/{filename:.*}", web::get().to(static_files))
-    })
-    .listen_uds(listener)?
-    .run()
-    .await
+            .wrap_fn(ratelimit::limit)
+            .wrap_fn(security::headers)
+            .wrap_fn(metrics::track)
+            .wrap_fn(logging::access)
+            .wrap_fn(proxy::resolve_client);
+        if site_metrics.on_site() {
+            app = app.route(&site_metrics.path, web::get().to(metrics::scrape));
+        }
+        app.configure(routes::configure)
+    };
+
+    // SIGTERM drains in-flight requests for up to `shutdown_timeout` seconds
+    let mut server = actix_server::Server::build()
+        .workers(server_config.worker_count())
+        .shutdown_timeout(server_config.shutdown_timeout);
+
+    for listener in site_listeners {
+        info!("Listening on {}", listener.describe());
+        server = listeners::serve(server, listener, app.clone(), server_config.proxy_protocol)?;
+    }
+    let mut server = server.run();
+
+    // Metrics on their own listener, out of reach of site traffic
+    let mut metrics_server = match metrics_listener {
+        Some(listener) => {
+            let path = metrics_config.path.clone();
+            let metrics_server = HttpServer::new(move || {
+                App::new().route(&path, web::get().to(metrics::scrape))
+            })
+            .workers(1)
+            .shutdown_timeout(server_config.shutdown_timeout);
+
+            info!("Serving metrics on {}{}", listener.describe(), metrics_config.path);
+            Some(match listener {
+                Listener::Unix(listener) => metrics_server.listen_uds(listener)?,
+                Listener::Tcp(listener) => metrics_server.listen(listener)?,
+            }.run())
+        }
+        None => None,
+    };
+
+    // The servers only start when first polled. Once that returns, the
+    // workers are up and accepting, so we are ready and a process we were
+    // upgraded from can stop; any earlier, it would drop connections.
+    if let Some(result) = listeners::start(&mut server).await {
+        return result;
+    }
+    if let Some(metrics_server) = metrics_server.as_mut() {
+        if let Some(result) = listeners::start(metrics_server).await {
+            return result;
+        }
+    }
+    state.mark_ready();
+    handoff.notify_parent();
+
+    match metrics_server {
+        Some(metrics_server) => {
+            let (site, metrics) = tokio::join!(server, metrics_server);
+            site.and(metrics)
+        }
+        None => server.await,
+    }
 }
\ No newline at end of file
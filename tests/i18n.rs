@@ -0,0 +1,47 @@
+use std::process::Command;
+
+use vvoss_web::libs::config::Config;
+use vvoss_web::libs::i18n::{self, Severity};
+
+const CONFIG: &str = "tests/fixtures/i18n/config.toml";
+
+#[test]
+fn check_reports_fixture_problems() {
+    let config = Config::load(Some(CONFIG), &[]).unwrap();
+    let report = i18n::check(&config).unwrap();
+
+    let mut findings: Vec<(bool, &str, Option<&str>, &str)> = report.findings.iter()
+        .map(|f| (f.severity == Severity::Error, f.kind, f.locale.as_deref(), f.key.as_str()))
+        .collect();
+    findings.sort();
+    // The plural forms of `items` are used through `t(key="items", count=...)`
+    assert_eq!(findings, [
+        (false, "identical", Some("en-EN"), "brand"),
+        (false, "orphaned", None, "unused"),
+        (false, "placeholder", Some("en-EN"), "welcome"),
+        (true, "duplicate", Some("de-DE"), "greeting"),
+        (true, "missing", Some("en-EN"), "only_de"),
+        (true, "placeholder", Some("en-EN"), "welcome"),
+    ]);
+    assert_eq!(report.errors(), 3);
+    assert_eq!(report.exit_code(), 1);
+}
+
+#[test]
+fn check_command_fails_on_errors() {
+    let output = Command::new(env!("CARGO_BIN_EXE_vvoss-web"))
+        .args(["i18n", "check", "--config", CONFIG])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert!(stdout.contains("error[missing] en-EN `only_de`: used in tests/fixtures/i18n/templates/page.tera:3"), "{}", stdout);
+    assert!(stdout.contains("error[placeholder] en-EN `welcome`: `{user}` is not an argument in de-DE"), "{}", stdout);
+    assert!(stdout.contains("warning[orphaned] `unused`"), "{}", stdout);
+    assert!(stdout.contains("error[duplicate] de-DE `greeting`: defined in \
+        tests/fixtures/i18n/templates/translations/strings.csv:2 and \
+        tests/fixtures/i18n/templates/translations/strings.csv:9"), "{}", stdout);
+    assert!(stdout.contains("warning[identical] en-EN `brand`: same text as de-DE"), "{}", stdout);
+    assert!(stdout.ends_with("3 errors, 3 warnings\n"), "{}", stdout);
+}
@@ -0,0 +1,72 @@
+use actix_web::{test, web, App};
+use actix_web_httpauth::middleware::HttpAuthentication;
+
+use vvoss_web::libs::auth::validator;
+use vvoss_web::libs::routes;
+use vvoss_web::libs::state::{Snapshot, Source, State};
+
+#[actix_web::test]
+async fn cookieless_mode_never_sets_cookies() {
+    let source = Source {
+        config_path: Some("config.toml".to_string()),
+        overrides: vec![
+            ("privacy.cookieless".to_string(), "true".to_string()),
+            ("auth.enabled".to_string(), "false".to_string()),
+        ],
+    };
+    let state = State::new(Snapshot::load(&source).unwrap(), source);
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .wrap(HttpAuthentication::with_fn(validator))
+            .configure(routes::configure),
+    )
+    .await;
+
+    let paths = [
+        "/",
+        "/portfolio",
+        "/en/",
+        "/de/",
+        "/en/?lang=de",
+        "/en/portfolio",
+        "/de/knowledge",
+        "/en/knowledge/missing",
+        "/en/impressum",
+        "/xx/",
+        "/static/css/base.css",
+        "/static/missing.css",
+    ];
+
+    for path in paths {
+        // With and without client state that would normally trigger cookies
+        for cookie in [None, Some("lang=de; screen_info=%7B%7D")] {
+            let mut req = test::TestRequest::get()
+                .uri(path)
+                .insert_header(("Accept-Language", "de-DE,de;q=0.9"))
+                .insert_header(("User-Agent", "Mozilla/5.0"));
+            if let Some(cookie) = cookie {
+                req = req.insert_header(("Cookie", cookie));
+            }
+
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert!(
+                resp.headers().get("set-cookie").is_none(),
+                "{} (cookie: {:?}) set a cookie",
+                path,
+                cookie
+            );
+
+            // The detection page writes a cookie from JavaScript
+            if resp.status().is_success() {
+                let body = test::read_body(resp).await;
+                assert!(
+                    !String::from_utf8_lossy(&body).contains("document.cookie"),
+                    "{} served the screen detection page",
+                    path
+                );
+            }
+        }
+    }
+}
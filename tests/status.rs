@@ -0,0 +1,53 @@
+use actix_web::{test, web, App};
+use actix_web_httpauth::middleware::HttpAuthentication;
+
+use vvoss_web::libs::auth::validator;
+use vvoss_web::libs::proxy;
+use vvoss_web::libs::routes;
+use vvoss_web::libs::state::{Snapshot, Source, State};
+
+fn state(token: Option<&str>) -> State {
+    let mut overrides = vec![
+        ("auth.enabled".to_string(), "true".to_string()),
+        ("server.trusted_proxies".to_string(), "127.0.0.1".to_string()),
+    ];
+    if let Some(token) = token {
+        overrides.push(("status.token".to_string(), token.to_string()));
+    }
+    let source = Source { config_path: Some("config.toml".to_string()), overrides };
+    State::new(Snapshot::load(&source).unwrap(), source)
+}
+
+#[actix_web::test]
+async fn status_needs_a_configured_token() {
+    for token in [None, Some("secret")] {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state(token)))
+                .wrap(HttpAuthentication::with_fn(validator))
+                .wrap_fn(proxy::resolve_client)
+                .configure(routes::configure),
+        )
+        .await;
+
+        // A remote client behind the proxy on loopback, and the proxy itself
+        let requests = [
+            test::TestRequest::get().insert_header(("X-Forwarded-For", "203.0.113.7")),
+            test::TestRequest::get(),
+        ];
+        for req in requests {
+            let req = req.uri("/status").peer_addr("127.0.0.1:40000".parse().unwrap());
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), 403, "token {:?}", token);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/status")
+            .peer_addr("127.0.0.1:40000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.7"))
+            .insert_header(("Authorization", "Bearer secret"));
+        let resp = test::call_service(&app, req.to_request()).await;
+        let expected = if token.is_some() { 200 } else { 403 };
+        assert_eq!(resp.status(), expected, "token {:?}", token);
+    }
+}